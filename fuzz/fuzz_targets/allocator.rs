@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Feeds random sequences of allocations and frees to one of the
+//! allocators and checks that the results never overlap each other or the
+//! reserved ranges, and that no capacity is lost.
+//!
+//! Input: the first Byte selects ArenaFlashAllocator, MultiArenaAllocator
+//! or BestFitAllocator. After that, each 4 Byte are one reserved range
+//! (the EFH for ArenaFlashAllocator, RESERVED_RANGES ones for the others):
+//! its distance from the end of the previous one (in units of 4 KiB) and
+//! its size. After that, each 4 Byte are one operation: bits 0 and 1 of
+//! the first Byte select take_at_least, take_at_least_ascending (or
+//! take_at_least if the allocator doesn't have it), free or an invalid free
+//! (of part of a taken range), and the other 3 Byte are the requested size
+//! (or which taken range to free). Operations after the first
+//! MAX_OPERATIONS are ignored.
+
+#![no_main]
+
+use core::convert::TryFrom;
+
+use amd_flash::allocators::{
+    ArenaFlashAllocator, BestFitAllocator, FlashAllocate, MultiArenaAllocator,
+};
+use amd_flash::{block, ErasableRange, Error, FlashAlign, Location};
+use libfuzzer_sys::fuzz_target;
+
+const ARENA_SIZE: usize = 0x100_0000; // B
+const RESERVED_RANGES: usize = 3;
+const MAX_OPERATIONS: usize = 256;
+/// Each operation needs at most two more entries, so they never run out
+/// (which would make free fail and lose free space).
+const ENTRIES: usize = 2 * MAX_OPERATIONS;
+
+fn overlap(a: &ErasableRange, b: &ErasableRange) -> bool {
+    Location::from(a.beginning) < Location::from(b.end)
+        && Location::from(b.beginning) < Location::from(a.end)
+}
+
+/// What the checks need from an allocator, beyond FlashAllocate.
+trait Subject: FlashAllocate + Sized {
+    /// take_at_least_ascending, if the allocator has it.
+    fn take_at_least_ascending(
+        &mut self,
+        size: usize,
+    ) -> Option<ErasableRange> {
+        self.take_at_least(size)
+    }
+    /// Checks what is specific to the allocator, and returns its free
+    /// ranges.
+    fn into_free_ranges(self) -> Vec<ErasableRange>;
+}
+
+impl Subject for ArenaFlashAllocator<ENTRIES> {
+    fn take_at_least_ascending(
+        &mut self,
+        size: usize,
+    ) -> Option<ErasableRange> {
+        self.take_at_least_ascending(size)
+    }
+    fn into_free_ranges(self) -> Vec<ErasableRange> {
+        assert_eq!(self.overhead(), self.efh_range().capacity());
+        let free_ranges: Vec<ErasableRange> = self.free_ranges().collect();
+        let (_, unused) = self.into_ranges();
+        for range in unused.iter().filter(|range| range.capacity() > 0) {
+            assert!(free_ranges.iter().any(|free| free.contains_range(range)));
+        }
+        free_ranges
+    }
+}
+
+impl Subject for MultiArenaAllocator<RESERVED_RANGES, ENTRIES> {
+    fn into_free_ranges(self) -> Vec<ErasableRange> {
+        let reserved: usize =
+            self.reserved().iter().map(|range| range.capacity()).sum();
+        assert_eq!(self.overhead(), reserved);
+        self.free_ranges().collect()
+    }
+}
+
+impl Subject for BestFitAllocator<RESERVED_RANGES, ENTRIES> {
+    fn into_free_ranges(self) -> Vec<ErasableRange> {
+        self.into_inner().into_free_ranges()
+    }
+}
+
+/// Reads N reserved ranges from the beginning of DATA, as (beginning,
+/// size), and returns them with the remainder of DATA.
+fn reserved_ranges<const N: usize>(
+    data: &[u8],
+    erasable_block_size: usize,
+) -> Option<([(Location, usize); N], &[u8])> {
+    if data.len() < 4 * N {
+        return None;
+    }
+    let (header, operations) = data.split_at(4 * N);
+    let mut result = [(0, 0); N];
+    let mut end = 0;
+    for (range, header) in result.iter_mut().zip(header.chunks_exact(4)) {
+        let distance = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let size = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let beginning = end + distance * erasable_block_size;
+        end = beginning
+            + (size + erasable_block_size - 1) / erasable_block_size
+                * erasable_block_size;
+        *range = (Location::try_from(beginning).ok()?, size);
+    }
+    Some((result, operations))
+}
+
+/// Does OPERATIONS with ALLOCATOR and checks the results.
+/// RESERVED are the reserved ranges that ALLOCATOR was made with, and
+/// RESERVED_RANGES the ones that it made of them.
+fn exercise<A: Subject>(
+    mut allocator: A,
+    reserved: &[(Location, usize)],
+    reserved_ranges: &[ErasableRange],
+    operations: &[u8],
+) {
+    let geometry = block::locations_for_code(1).unwrap();
+    let erasable_block_size = geometry.erasable_block_size();
+    assert_eq!(reserved_ranges.len(), reserved.len());
+    for (range, &(beginning, size)) in reserved_ranges.iter().zip(reserved) {
+        assert_eq!(Location::from(range.beginning), beginning);
+        assert!(range.capacity() >= size);
+        assert!(range.capacity() < size + erasable_block_size);
+        assert!(Location::from(range.end) as usize <= ARENA_SIZE);
+    }
+    let overhead: usize =
+        reserved_ranges.iter().map(|range| range.capacity()).sum();
+
+    let mut taken: Vec<ErasableRange> = Vec::new();
+    for operation in operations.chunks_exact(4).take(MAX_OPERATIONS) {
+        let size =
+            u32::from_le_bytes([operation[1], operation[2], operation[3], 0])
+                as usize;
+        let before = allocator.max_contiguous_capacity();
+        let range = match operation[0] & 3 {
+            0 => allocator.take_at_least(size),
+            1 => allocator.take_at_least_ascending(size),
+            2 => {
+                if !taken.is_empty() {
+                    let range = taken.swap_remove(size % taken.len());
+                    allocator.free(range).unwrap();
+                }
+                continue;
+            }
+            _ => {
+                if let Some(range) = taken.get(size % taken.len().max(1)) {
+                    if range.capacity() > erasable_block_size {
+                        let part = ErasableRange::new(
+                            range.beginning.next_block().unwrap(),
+                            range.end,
+                        );
+                        assert!(matches!(
+                            allocator.free(part),
+                            Err(Error::Programmer)
+                        ));
+                    }
+                }
+                continue;
+            }
+        };
+        match range {
+            Some(range) => {
+                assert!(range.capacity() >= size);
+                assert!(range.capacity() < size + erasable_block_size);
+                assert!(geometry.is_aligned(range.beginning.into()));
+                for other in reserved_ranges.iter().chain(&taken) {
+                    assert!(!overlap(&range, other));
+                }
+                // Empty ranges don't take any space.
+                if range.capacity() > 0 {
+                    taken.push(range);
+                }
+            }
+            None => {
+                // take_at_least only fails if nothing is big enough.
+                if operation[0] & 3 == 0 {
+                    assert!(before < size);
+                }
+            }
+        }
+    }
+
+    let taken_capacity: usize =
+        taken.iter().map(|range| range.capacity()).sum();
+    let free_ranges = allocator.into_free_ranges();
+    for free in &free_ranges {
+        for range in reserved_ranges.iter().chain(&taken) {
+            assert!(!overlap(free, range));
+        }
+    }
+    let free_capacity: usize =
+        free_ranges.iter().map(|range| range.capacity()).sum();
+    assert_eq!(taken_capacity + free_capacity + overhead, ARENA_SIZE);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (&selector, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let geometry = block::locations_for_code(1).unwrap();
+    let erasable_block_size = geometry.erasable_block_size();
+    let beginning = geometry.erasable_location(0).unwrap();
+    let arena =
+        ErasableRange::new(beginning, beginning.advance(ARENA_SIZE).unwrap());
+    match selector % 3 {
+        0 => {
+            let ([efh], operations) =
+                match reserved_ranges::<1>(data, erasable_block_size) {
+                    Some(result) => result,
+                    None => return,
+                };
+            let (efh_beginning, efh_size) = efh;
+            if let Ok(allocator) = ArenaFlashAllocator::<ENTRIES>::with_entries(
+                efh_beginning,
+                efh_size,
+                arena,
+            ) {
+                let efh_range = allocator.efh_range();
+                let efh_range =
+                    ErasableRange::new(efh_range.beginning, efh_range.end);
+                exercise(allocator, &[efh], &[efh_range], operations);
+            }
+        }
+        selector => {
+            let parsed = reserved_ranges(data, erasable_block_size);
+            let (reserved, operations) = match parsed {
+                Some(result) => result,
+                None => return,
+            };
+            let arenas: MultiArenaAllocator<RESERVED_RANGES, ENTRIES> =
+                match MultiArenaAllocator::with_entries(reserved, arena) {
+                    Ok(arenas) => arenas,
+                    Err(_) => return,
+                };
+            let reserved_ranges: Vec<ErasableRange> = arenas
+                .reserved()
+                .iter()
+                .map(|range| ErasableRange::new(range.beginning, range.end))
+                .collect();
+            if selector == 1 {
+                exercise(arenas, &reserved, &reserved_ranges, operations);
+            } else {
+                let allocator = BestFitAllocator::new(arenas);
+                exercise(allocator, &reserved, &reserved_ranges, operations);
+            }
+        }
+    }
+});
@@ -1,14 +1,514 @@
-use crate::{ErasableRange, Location};
+use crate::block;
+use crate::{
+    ErasableLocation, ErasableRange, FlashAlign, FlashWrite, Location,
+};
 use crate::{Error, Result};
 
 pub trait FlashAllocate {
     fn take_at_least(&mut self, size: usize) -> Option<ErasableRange>;
     fn max_contiguous_capacity(&self) -> usize;
+    /// Gives RANGE (returned by take_at_least before) back, so that it can
+    /// be taken again.
+    /// If RANGE was never taken (or was given back already), returns
+    /// Error::Programmer.
+    /// By default, giving back is not supported (and always returns
+    /// Error::Programmer).
+    fn free(&mut self, _range: ErasableRange) -> Result<()> {
+        Err(Error::Programmer)
+    }
+    /// Takes a range of at least SIZE Bytes (otherwise returns
+    /// Error::Size) and erases it on FLASH.
+    fn alloc_and_erase<F: FlashWrite>(
+        &mut self,
+        flash: &F,
+        size: usize,
+    ) -> Result<ErasableRange>
+    where
+        Self: Sized,
+    {
+        let range = self.take_at_least(size).ok_or(Error::Size)?;
+        flash.erase_range(&range)?;
+        Ok(range)
+    }
+    /// Takes a range of at least SIZE Bytes that starts at a multiple of
+    /// ALIGN (otherwise returns Error::Size).
+    /// ALIGN has to be a multiple of the erasable block size (otherwise
+    /// returns Error::Alignment).
+    /// By default, this takes enough extra space to be able to align the
+    /// beginning, whatever it turns out to be; the extra space is padding
+    /// and stays taken.
+    fn take_aligned(
+        &mut self,
+        size: usize,
+        align: block::Size,
+    ) -> Result<ErasableRange> {
+        let padding = usize::from(align) - 1;
+        let padded_size = size.checked_add(padding).ok_or(Error::Size)?;
+        let mut range = self
+            .take_at_least(padded_size)
+            .ok_or(Error::Size)?
+            .align_start_up(align)?;
+        range.take_at_least(size).ok_or(Error::Size)
+    }
+    /// Takes the biggest range that is available, but at most MAX_SIZE
+    /// Bytes (rounded up to whole erasable blocks).
+    /// If nothing is available, returns None.
+    fn alloc_up_to(&mut self, max_size: usize) -> Option<ErasableRange> {
+        let size = self.max_contiguous_capacity().min(max_size);
+        if size == 0 {
+            return None;
+        }
+        self.take_at_least(size)
+    }
+}
+
+/// Default number of entries that a MultiArenaAllocator has for keeping
+/// track of ranges (see there).
+pub const DEFAULT_ENTRIES: usize = 16;
+
+/// A range that was handed out (and can be given back), or that is free
+/// again, below the unused space of its gap.
+#[derive(Clone, Copy)]
+struct Entry {
+    beginning: Location,
+    end: Location,
+    handed_out: bool,
+}
+
+/// Allocator that uses an arena except for N reserved ranges in it.
+/// Each gap between the reserved ranges is used from the bottom up.
+/// Up to M entries keep track of ranges that were handed out and of free
+/// ranges between them, so that ranges can be given back in any order and
+/// taken again.
+/// When all M entries are in use, ranges are still handed out, but they
+/// can not be given back (free returns Error::Programmer), and free space
+/// that a new range skips is lost.
+/// Note: With M = 0, this is a plain bump allocator.
+pub struct MultiArenaAllocator<const N: usize, const M: usize = DEFAULT_ENTRIES>
+{
+    /// Beginning of the arena
+    beginning: ErasableLocation,
+    /// End of the arena
+    end: ErasableLocation,
+    reserved: [ErasableRange; N],
+    /// For each gap below a reserved range, where the part of it that was
+    /// never handed out starts
+    unused: [Location; N],
+    /// Where the part of the gap above the last reserved range that was
+    /// never handed out starts
+    top_unused: Location,
+    /// The (non-empty) entries, in ascending order
+    entries: [Entry; M],
+    entry_count: usize,
+}
+
+impl<const N: usize> MultiArenaAllocator<N> {
+    /// Creates a new allocator that will use the given ARENA except for the
+    /// RESERVED ranges, each given by its beginning and its size.
+    /// RESERVED has to be in ascending order and must not overlap
+    /// (otherwise returns Error::Programmer) and the beginnings have to be
+    /// on erasable block boundaries (otherwise returns Error::Alignment).
+    pub fn new(
+        reserved: [(Location, usize); N],
+        arena: ErasableRange,
+    ) -> Result<Self> {
+        Self::with_entries(reserved, arena)
+    }
+}
+
+impl<const N: usize, const M: usize> MultiArenaAllocator<N, M> {
+    /// Like new, but with M entries instead of DEFAULT_ENTRIES.
+    pub fn with_entries(
+        reserved: [(Location, usize); N],
+        arena: ErasableRange,
+    ) -> Result<Self> {
+        let mut arena = arena;
+        let beginning = arena.beginning;
+        let empty = || ErasableRange::new(arena.beginning, arena.beginning);
+        let mut reserved_ranges = core::array::from_fn(|_| empty());
+        for (i, &(beginning, size)) in reserved.iter().enumerate() {
+            let below_size = (beginning as usize)
+                .checked_sub(Location::from(arena.beginning) as usize)
+                .ok_or(Error::Programmer)?;
+            arena.take_exact(below_size)?;
+            reserved_ranges[i] =
+                arena.take_at_least(size).ok_or(Error::Size)?;
+        }
+        let unused = core::array::from_fn(|i| match i.checked_sub(1) {
+            Some(i) => Location::from(reserved_ranges[i].end),
+            None => Location::from(beginning),
+        });
+        let top_unused =
+            reserved_ranges.last().map_or(beginning, |range| range.end).into();
+        Ok(Self {
+            beginning,
+            end: arena.end,
+            reserved: reserved_ranges,
+            unused,
+            top_unused,
+            entries: [Entry { beginning: 0, end: 0, handed_out: false }; M],
+            entry_count: 0,
+        })
+    }
+    /// The reserved ranges, in ascending order.
+    pub fn reserved(&self) -> &[ErasableRange; N] {
+        &self.reserved
+    }
+    /// Number of Bytes of the arena that can never be allocated, that is
+    /// the reserved ranges including their rounding up to whole erasable
+    /// blocks.
+    pub fn overhead(&self) -> usize {
+        self.reserved.iter().map(|range| range.capacity()).sum()
+    }
+    /// The free ranges, in ascending order.
+    pub fn free_ranges(&self) -> FreeRanges<'_, N, M> {
+        FreeRanges { arenas: self, gap: 0, entry: 0 }
+    }
+    fn entries(&self) -> &[Entry] {
+        &self.entries[..self.entry_count]
+    }
+    fn range(&self, beginning: Location, end: Location) -> ErasableRange {
+        let erasable_block_size = self.beginning.erasable_block_size;
+        ErasableRange::new(
+            ErasableLocation { location: beginning, erasable_block_size },
+            ErasableLocation { location: end, erasable_block_size },
+        )
+    }
+    /// The part of gap GAP (0..=N, below reserved range GAP) that was never
+    /// handed out, as (beginning, end).
+    fn unused(&self, gap: usize) -> (Location, Location) {
+        match self.reserved.get(gap) {
+            Some(reserved) => (self.unused[gap], reserved.beginning.into()),
+            None => (self.top_unused, self.end.into()),
+        }
+    }
+    fn unused_mut(&mut self, gap: usize) -> &mut Location {
+        match self.unused.get_mut(gap) {
+            Some(unused) => unused,
+            None => &mut self.top_unused,
+        }
+    }
+    /// The gap that LOCATION (which is not in a reserved range) is in.
+    fn gap(&self, location: Location) -> usize {
+        self.reserved
+            .iter()
+            .take_while(|range| Location::from(range.beginning) <= location)
+            .count()
+    }
+    /// If SIZE is 0, returns an empty range (even if nothing is free).
+    fn nothing(&self, size: usize) -> Option<ErasableRange> {
+        let beginning =
+            self.free_ranges().next().map_or(self.end, |range| range.beginning);
+        (size == 0).then(|| ErasableRange::new(beginning, beginning))
+    }
+    /// Adds ENTRY (unless it is empty), if not all entries are in use.
+    fn insert(&mut self, entry: Entry) {
+        let count = self.entry_count;
+        if entry.beginning == entry.end || count == M {
+            return;
+        }
+        let i =
+            self.entries().partition_point(|e| e.beginning < entry.beginning);
+        self.entries.copy_within(i..count, i + 1);
+        self.entries[i] = entry;
+        self.entry_count += 1;
+    }
+    fn remove(&mut self, i: usize) -> Entry {
+        let entry = self.entries[i];
+        self.entries.copy_within(i + 1..self.entry_count, i);
+        self.entry_count -= 1;
+        entry
+    }
+    /// Marks RANGE (which has to be within a free range) as handed out, and
+    /// returns it.
+    fn hand_out(&mut self, range: ErasableRange) -> ErasableRange {
+        let beginning = Location::from(range.beginning);
+        let end = Location::from(range.end);
+        if beginning == end {
+            return range;
+        }
+        let free = self.entries().iter().position(|entry| {
+            !entry.handed_out
+                && entry.beginning <= beginning
+                && end <= entry.end
+        });
+        let (below, above) = match free {
+            Some(i) => {
+                let entry = self.remove(i);
+                (entry.beginning, entry.end)
+            }
+            None => {
+                let gap = self.gap(beginning);
+                let unused = self.unused_mut(gap);
+                let below = *unused;
+                *unused = end;
+                (below, end)
+            }
+        };
+        // The free space around RANGE first, so that as little as possible
+        // is lost.
+        self.insert(Entry {
+            beginning: below,
+            end: beginning,
+            handed_out: false,
+        });
+        self.insert(Entry { beginning: end, end: above, handed_out: false });
+        self.insert(Entry { beginning, end, handed_out: true });
+        range
+    }
+    /// Like take_at_least, but doesn't return a range that crosses a
+    /// multiple of BOUNDARY unnecessarily (see
+    /// ErasableRange::crosses_boundary): if necessary, it skips the free
+    /// space up to the next multiple of BOUNDARY (which stays free).
+    pub fn take_at_least_avoiding(
+        &mut self,
+        size: usize,
+        boundary: block::Size,
+    ) -> Option<ErasableRange> {
+        let range = self.free_ranges().find_map(|range| {
+            let candidate = ErasableRange::new(range.beginning, range.end)
+                .take_at_least(size)?;
+            if !candidate.crosses_boundary(boundary) {
+                return Some(candidate);
+            }
+            range.align_start_up(boundary).ok()?.take_at_least(size)
+        })?;
+        Some(self.hand_out(range))
+    }
+    /// The free ranges, each with its beginning moved up to the first
+    /// multiple of ALIGN (free ranges without one are left out).
+    /// ALIGN has to be a multiple of the erasable block size (otherwise
+    /// returns Error::Alignment).
+    fn aligned_free_ranges(
+        &self,
+        align: block::Size,
+    ) -> Result<impl Iterator<Item = ErasableRange> + '_> {
+        if usize::from(align) % self.beginning.erasable_block_size != 0 {
+            return Err(Error::Alignment);
+        }
+        Ok(self
+            .free_ranges()
+            .filter_map(move |range| range.align_start_up(align).ok()))
+    }
+    /// Returns a view of the (current) free space as one contiguous
+    /// region.
+    pub fn virtual_arena(&self) -> VirtualArena<'_, N, M> {
+        VirtualArena { arenas: self }
+    }
+}
+
+/// The free ranges of a MultiArenaAllocator, in ascending order.
+pub struct FreeRanges<'a, const N: usize, const M: usize = DEFAULT_ENTRIES> {
+    arenas: &'a MultiArenaAllocator<N, M>,
+    /// The gap of the next free range
+    gap: usize,
+    /// Index of the next entry
+    entry: usize,
+}
+
+impl<const N: usize, const M: usize> Iterator for FreeRanges<'_, N, M> {
+    type Item = ErasableRange;
+    fn next(&mut self) -> Option<ErasableRange> {
+        let arenas = self.arenas;
+        while self.gap <= N {
+            let (unused, end) = arenas.unused(self.gap);
+            // The entries of the gap are below its unused part.
+            if let Some(entry) = arenas
+                .entries()
+                .get(self.entry)
+                .filter(|entry| entry.beginning < unused)
+            {
+                self.entry += 1;
+                if !entry.handed_out {
+                    return Some(arenas.range(entry.beginning, entry.end));
+                }
+                continue;
+            }
+            self.gap += 1;
+            if unused < end {
+                return Some(arenas.range(unused, end));
+            }
+        }
+        None
+    }
+}
+
+/// The free ranges of a MultiArenaAllocator, one after the other, as one
+/// contiguous virtual region [0, size()).
+pub struct VirtualArena<'a, const N: usize, const M: usize = DEFAULT_ENTRIES> {
+    arenas: &'a MultiArenaAllocator<N, M>,
+}
+
+impl<const N: usize, const M: usize> VirtualArena<'_, N, M> {
+    /// Total size of the free ranges, in Byte.
+    pub fn size(&self) -> usize {
+        self.arenas.free_ranges().map(|range| range.capacity()).sum()
+    }
+    /// Returns the real location of the virtual OFFSET, if it is within
+    /// the region.
+    pub fn location(&self, offset: usize) -> Option<Location> {
+        let mut offset = offset;
+        for range in self.arenas.free_ranges() {
+            let capacity = range.capacity();
+            if offset < capacity {
+                return Some(
+                    Location::from(range.beginning) + offset as Location,
+                );
+            }
+            offset -= capacity;
+        }
+        None
+    }
+}
+
+impl<const N: usize, const M: usize> FlashAlign for MultiArenaAllocator<N, M> {
+    fn erasable_block_size(&self) -> usize {
+        self.beginning.erasable_block_size
+    }
+}
+
+impl<const N: usize, const M: usize> FlashAllocate
+    for MultiArenaAllocator<N, M>
+{
+    /// From the free ranges, in order, take a range of at least SIZE Bytes,
+    /// if possible. Otherwise return None.
+    /// This includes sizes that overflow when rounded up.
+    fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+        let range = self
+            .free_ranges()
+            .find_map(|mut range| range.take_at_least(size))
+            .or_else(|| self.nothing(size))?;
+        Some(self.hand_out(range))
+    }
+    /// From the free ranges, in order, take a range of at least SIZE Bytes
+    /// that starts at a multiple of ALIGN, so that the space in front of it
+    /// stays free.
+    fn take_aligned(
+        &mut self,
+        size: usize,
+        align: block::Size,
+    ) -> Result<ErasableRange> {
+        let range = self
+            .aligned_free_ranges(align)?
+            .find_map(|mut range| range.take_at_least(size))
+            .ok_or(Error::Size)?;
+        Ok(self.hand_out(range))
+    }
+    fn max_contiguous_capacity(&self) -> usize {
+        self.free_ranges().map(|range| range.capacity()).max().unwrap_or(0)
+    }
+    /// Gives RANGE back, merging it with the free space around it.
+    /// RANGE has to be exactly a range that was handed out before (while an
+    /// entry was available for it) and not given back yet (otherwise
+    /// returns Error::Programmer).
+    /// Note: Giving back an empty range does nothing.
+    fn free(&mut self, range: ErasableRange) -> Result<()> {
+        let beginning = Location::from(range.beginning);
+        let end = Location::from(range.end);
+        if beginning == end {
+            return Ok(());
+        }
+        let i = self
+            .entries()
+            .iter()
+            .position(|entry| {
+                entry.handed_out
+                    && entry.beginning == beginning
+                    && entry.end == end
+            })
+            .ok_or(Error::Programmer)?;
+        let mut free = self.remove(i);
+        free.handed_out = false;
+        if let Some(&above) = self.entries().get(i) {
+            if !above.handed_out && above.beginning == free.end {
+                free.end = self.remove(i).end;
+            }
+        }
+        if let Some(&below) = i.checked_sub(1).map(|i| &self.entries[i]) {
+            if !below.handed_out && below.end == free.beginning {
+                free.beginning = self.remove(i - 1).beginning;
+            }
+        }
+        let gap = self.gap(free.beginning);
+        let unused = self.unused_mut(gap);
+        if *unused == free.end {
+            *unused = free.beginning;
+        } else {
+            // There is room, since the entry of RANGE was removed.
+            self.insert(free);
+        }
+        Ok(())
+    }
 }
 
-pub struct ArenaFlashAllocator {
-    _efh_range: ErasableRange,
-    free_ranges: [ErasableRange; 2],
+/// Like MultiArenaAllocator, but takes each range from the free range
+/// that has the least space left afterwards (the one with the lowest
+/// address if several do), so that big free ranges stay available.
+pub struct BestFitAllocator<const N: usize, const M: usize = DEFAULT_ENTRIES> {
+    arenas: MultiArenaAllocator<N, M>,
+}
+
+impl<const N: usize, const M: usize> BestFitAllocator<N, M> {
+    pub fn new(arenas: MultiArenaAllocator<N, M>) -> Self {
+        Self { arenas }
+    }
+    pub fn into_inner(self) -> MultiArenaAllocator<N, M> {
+        self.arenas
+    }
+}
+
+impl<const N: usize, const M: usize> FlashAlign for BestFitAllocator<N, M> {
+    fn erasable_block_size(&self) -> usize {
+        self.arenas.erasable_block_size()
+    }
+}
+
+impl<const N: usize, const M: usize> FlashAllocate for BestFitAllocator<N, M> {
+    fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+        let range = self
+            .arenas
+            .free_ranges()
+            .filter_map(|mut range| {
+                let result = range.take_at_least(size)?;
+                Some((range.capacity(), result))
+            })
+            .min_by_key(|(remainder, _)| *remainder)
+            .map(|(_, range)| range)
+            .or_else(|| self.arenas.nothing(size))?;
+        Some(self.arenas.hand_out(range))
+    }
+    fn take_aligned(
+        &mut self,
+        size: usize,
+        align: block::Size,
+    ) -> Result<ErasableRange> {
+        let range = self
+            .arenas
+            .aligned_free_ranges(align)?
+            .filter_map(|mut range| {
+                let result = range.take_at_least(size)?;
+                Some((range.capacity(), result))
+            })
+            .min_by_key(|(remainder, _)| *remainder)
+            .map(|(_, range)| range)
+            .ok_or(Error::Size)?;
+        Ok(self.arenas.hand_out(range))
+    }
+    fn max_contiguous_capacity(&self) -> usize {
+        self.arenas.max_contiguous_capacity()
+    }
+    fn free(&mut self, range: ErasableRange) -> Result<()> {
+        self.arenas.free(range)
+    }
+}
+
+/// Allocator that uses an arena except for the EFH.
+/// M is the number of entries for keeping track of ranges (see
+/// MultiArenaAllocator).
+pub struct ArenaFlashAllocator<const M: usize = DEFAULT_ENTRIES> {
+    arenas: MultiArenaAllocator<1, M>,
+    /// End of the last range returned by take_at_least_ascending
+    ascending_floor: Location,
 }
 
 impl ArenaFlashAllocator {
@@ -21,53 +521,334 @@ impl ArenaFlashAllocator {
         efh_size: usize,
         arena: ErasableRange,
     ) -> Result<Self> {
-        let mut arena = arena;
+        Self::with_entries(efh_beginning, efh_size, arena)
+    }
+    /// Like new, but first makes sure that the ARENA is at most
+    /// MAX_CAPACITY Byte (otherwise returns Error::Size), in order to catch
+    /// unit mistakes in the arena size.
+    pub fn with_max_capacity(
+        efh_beginning: Location,
+        efh_size: usize,
+        arena: ErasableRange,
+        max_capacity: usize,
+    ) -> Result<Self> {
+        if arena.capacity() > max_capacity {
+            return Err(Error::Size);
+        }
+        Self::new(efh_beginning, efh_size, arena)
+    }
+    /// Like new, but places the EFH EFH_BACK_OFFSET Byte before the end of
+    /// the ARENA instead of at a fixed location.
+    pub fn new_efh_from_end(
+        efh_back_offset: usize,
+        efh_size: usize,
+        arena: ErasableRange,
+    ) -> Result<Self> {
+        let efh_beginning =
+            arena.capacity().checked_sub(efh_back_offset).ok_or(Error::Size)?;
+        if efh_beginning & (arena.beginning.erasable_block_mask() as usize) != 0
+        {
+            return Err(Error::Alignment);
+        }
+        Self::new(efh_beginning as Location, efh_size, arena)
+    }
+}
+
+impl<const M: usize> ArenaFlashAllocator<M> {
+    /// Like new, but with M entries instead of DEFAULT_ENTRIES.
+    pub fn with_entries(
+        efh_beginning: Location,
+        efh_size: usize,
+        arena: ErasableRange,
+    ) -> Result<Self> {
         assert!(Location::from(arena.beginning) == 0);
         // Avoid EFH_BEGINNING..(EFH_BEGINNING + EFH_SIZE)
-        let a_size = efh_beginning as usize;
-        let a = arena.take_at_least(a_size).ok_or(Error::Size)?;
-        assert!(Location::from(a.end) as usize == a_size);
-        let _efh_range = arena.take_at_least(efh_size).ok_or(Error::Size)?;
-        Ok(Self { _efh_range, free_ranges: [a, arena] })
+        let arenas = MultiArenaAllocator::with_entries(
+            [(efh_beginning, efh_size)],
+            arena,
+        )?;
+        Ok(Self { arenas, ascending_floor: 0 })
+    }
+    /// The range reserved for the EFH.
+    pub fn efh_range(&self) -> &ErasableRange {
+        &self.arenas.reserved[0]
+    }
+    /// Number of Bytes of the arena that can never be allocated, that is
+    /// the EFH reservation including its rounding up to whole erasable
+    /// blocks.
+    pub fn overhead(&self) -> usize {
+        self.arenas.overhead()
+    }
+    /// See MultiArenaAllocator::take_at_least_avoiding.
+    pub fn take_at_least_avoiding(
+        &mut self,
+        size: usize,
+        boundary: block::Size,
+    ) -> Option<ErasableRange> {
+        self.arenas.take_at_least_avoiding(size, boundary)
+    }
+    /// Returns a view of the (current) free space below and above the EFH
+    /// as one contiguous region.
+    pub fn virtual_arena(&self) -> VirtualArena<'_, 1, M> {
+        self.arenas.virtual_arena()
+    }
+    /// The free ranges, in ascending order.
+    pub fn free_ranges(&self) -> FreeRanges<'_, 1, M> {
+        self.arenas.free_ranges()
+    }
+    /// Like take_at_least, but never returns a range below one that a
+    /// previous call returned, so consecutive results are in ascending
+    /// address order.
+    /// Free space below the previous result is skipped (but stays
+    /// available to take_at_least).
+    pub fn take_at_least_ascending(
+        &mut self,
+        size: usize,
+    ) -> Option<ErasableRange> {
+        let floor = self.ascending_floor;
+        let arenas = &self.arenas;
+        let range = arenas
+            .free_ranges()
+            .filter(|range| Location::from(range.end) > floor)
+            .find_map(|range| {
+                let beginning = Location::from(range.beginning).max(floor);
+                arenas.range(beginning, range.end.into()).take_at_least(size)
+            })?;
+        let result = self.arenas.hand_out(range);
+        self.ascending_floor = Location::from(result.end);
+        Some(result)
+    }
+    /// Returns the range reserved for the EFH and, below and above it, the
+    /// free space above the ranges that are still handed out there.
+    /// Note: Free space between handed-out ranges (see free_ranges) is not
+    /// included.
+    pub fn into_ranges(self) -> (ErasableRange, [ErasableRange; 2]) {
+        let arenas = &self.arenas;
+        let [below, above] = [0, 1].map(|gap| {
+            let (beginning, end) = arenas.unused(gap);
+            arenas.range(beginning, end)
+        });
+        let [efh_range] = self.arenas.reserved;
+        (efh_range, [below, above])
     }
 }
-impl FlashAllocate for ArenaFlashAllocator {
-    /// From the free ranges, take a range of at least SIZE Bytes,
-    /// if possible. Otherwise return None.
+impl<const M: usize> FlashAlign for ArenaFlashAllocator<M> {
+    fn erasable_block_size(&self) -> usize {
+        self.arenas.erasable_block_size()
+    }
+}
+impl<const M: usize> FlashAllocate for ArenaFlashAllocator<M> {
     fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
-        self.free_ranges[0]
-            .take_at_least(size)
-            .or_else(|| self.free_ranges[1].take_at_least(size))
+        self.arenas.take_at_least(size)
+    }
+    fn take_aligned(
+        &mut self,
+        size: usize,
+        align: block::Size,
+    ) -> Result<ErasableRange> {
+        self.arenas.take_aligned(size, align)
     }
     fn max_contiguous_capacity(&self) -> usize {
-        let mut max_capacity = 0usize;
-        for range in &self.free_ranges {
-            let capacity = range.capacity();
-            if capacity > max_capacity {
-                max_capacity = capacity
+        self.arenas.max_contiguous_capacity()
+    }
+    fn free(&mut self, range: ErasableRange) -> Result<()> {
+        self.arenas.free(range)
+    }
+}
+
+/// Hands out consecutive erasable-block-aligned parts of an already
+/// allocated range, for laying out the inside of that range (for example a
+/// directory followed by its entries).
+pub struct SubAllocator {
+    remainder: ErasableRange,
+}
+
+impl SubAllocator {
+    pub fn new(range: ErasableRange) -> Self {
+        Self { remainder: range }
+    }
+    /// Takes the next part of at least SIZE Byte (rounded up to whole
+    /// erasable blocks), if it still fits.
+    pub fn take(&mut self, size: usize) -> Option<ErasableRange> {
+        self.remainder.take_at_least(size)
+    }
+    /// Number of Byte that have not been taken yet.
+    pub fn remaining(&self) -> usize {
+        self.remainder.capacity()
+    }
+    /// Returns the part of the range that has not been taken yet.
+    pub fn into_remainder(self) -> ErasableRange {
+        self.remainder
+    }
+}
+
+/// Allocator that refuses to hand out more than MAX_SIZE Byte in a single
+/// allocation, and otherwise delegates to the wrapped allocator.
+/// Note: The cap applies to the range actually handed out, that is after
+/// rounding up to the erasable block size of the wrapped allocator.
+pub struct Capped<A: FlashAllocate + FlashAlign> {
+    allocator: A,
+    max_size: usize,
+}
+
+impl<A: FlashAllocate + FlashAlign> Capped<A> {
+    pub fn new(allocator: A, max_size: usize) -> Self {
+        Self { allocator, max_size }
+    }
+    pub fn into_inner(self) -> A {
+        self.allocator
+    }
+    /// Returns whether a range of at least SIZE Byte (rounded up to whole
+    /// erasable blocks) stays within the cap.
+    fn fits(&self, size: usize) -> bool {
+        let mask = self.allocator.erasable_block_size() - 1;
+        size.checked_add(mask)
+            .map_or(false, |size| size & !mask <= self.max_size)
+    }
+}
+impl<A: FlashAllocate + FlashAlign> FlashAlign for Capped<A> {
+    fn erasable_block_size(&self) -> usize {
+        self.allocator.erasable_block_size()
+    }
+}
+impl<A: FlashAllocate + FlashAlign> FlashAllocate for Capped<A> {
+    fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+        if !self.fits(size) {
+            return None;
+        }
+        self.allocator.take_at_least(size)
+    }
+    fn take_aligned(
+        &mut self,
+        size: usize,
+        align: block::Size,
+    ) -> Result<ErasableRange> {
+        if !self.fits(size) {
+            return Err(Error::Size);
+        }
+        self.allocator.take_aligned(size, align)
+    }
+    /// Note: The cap is rounded down to whole erasable blocks, so that
+    /// taking that much works.
+    fn max_contiguous_capacity(&self) -> usize {
+        let mask = self.allocator.erasable_block_size() - 1;
+        self.allocator.max_contiguous_capacity().min(self.max_size & !mask)
+    }
+    fn free(&mut self, range: ErasableRange) -> Result<()> {
+        self.allocator.free(range)
+    }
+}
+
+/// Allocator that remembers each allocation under a name, so it can be
+/// looked up later.
+#[cfg(feature = "std")]
+pub struct NamedAllocator<A: FlashAllocate> {
+    allocator: A,
+    entries: Vec<(String, ErasableRange)>,
+}
+
+#[cfg(feature = "std")]
+impl<A: FlashAllocate> NamedAllocator<A> {
+    pub fn new(allocator: A) -> Self {
+        Self { allocator, entries: Vec::new() }
+    }
+    /// Takes a range of at least SIZE Bytes and remembers it under NAME.
+    /// NAME must not have been used before.
+    pub fn alloc_named(
+        &mut self,
+        name: &str,
+        size: usize,
+    ) -> Result<&ErasableRange> {
+        if self.get(name).is_some() {
+            return Err(Error::Programmer);
+        }
+        let range = self.allocator.take_at_least(size).ok_or(Error::Size)?;
+        self.entries.push((name.into(), range));
+        Ok(&self.entries[self.entries.len() - 1].1)
+    }
+    pub fn get(&self, name: &str) -> Option<&ErasableRange> {
+        self.entries
+            .iter()
+            .find(|(entry_name, _)| entry_name == name)
+            .map(|(_, range)| range)
+    }
+    /// All allocations, in the order they were made.
+    pub fn entries(&self) -> &[(String, ErasableRange)] {
+        &self.entries
+    }
+    pub fn into_inner(self) -> A {
+        self.allocator
+    }
+}
+
+/// A difference between two layouts (see diff_layouts).
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum LayoutChange {
+    Added { name: String, beginning: Location, size: usize },
+    Removed { name: String, beginning: Location, size: usize },
+    Moved { name: String, old_beginning: Location, new_beginning: Location },
+    Resized { name: String, old_size: usize, new_size: usize },
+}
+
+/// Compares two layouts of named ranges (for example
+/// NamedAllocator::entries) and returns what changed from OLD to NEW.
+/// An entry that both moved and was resized is reported twice.
+#[cfg(feature = "std")]
+pub fn diff_layouts(
+    old: &[(String, ErasableRange)],
+    new: &[(String, ErasableRange)],
+) -> Vec<LayoutChange> {
+    let find = |entries: &[(String, ErasableRange)], name: &str| {
+        entries.iter().find(|(entry_name, _)| entry_name == name).map(
+            |(_, range)| (Location::from(range.beginning), range.capacity()),
+        )
+    };
+    let mut result = Vec::new();
+    for (name, old_range) in old {
+        let old_beginning = Location::from(old_range.beginning);
+        let old_size = old_range.capacity();
+        match find(new, name) {
+            None => result.push(LayoutChange::Removed {
+                name: name.clone(),
+                beginning: old_beginning,
+                size: old_size,
+            }),
+            Some((new_beginning, new_size)) => {
+                if new_beginning != old_beginning {
+                    result.push(LayoutChange::Moved {
+                        name: name.clone(),
+                        old_beginning,
+                        new_beginning,
+                    });
+                }
+                if new_size != old_size {
+                    result.push(LayoutChange::Resized {
+                        name: name.clone(),
+                        old_size,
+                        new_size,
+                    });
+                }
             }
         }
-        max_capacity
     }
+    for (name, new_range) in new {
+        if find(old, name).is_none() {
+            result.push(LayoutChange::Added {
+                name: name.clone(),
+                beginning: Location::from(new_range.beginning),
+                size: new_range.capacity(),
+            });
+        }
+    }
+    result
 }
 
 #[cfg(test)]
 mod allocator_tests {
-    use super::super::{FlashAlign, Location};
+    use super::super::{FlashAlign, FlashRead, Location};
     use super::*;
-    fn intersect(
-        a: &ErasableRange,
-        b: &ErasableRange,
-    ) -> Option<(Location, Location)> {
-        let new_beginning =
-            Location::from(a.beginning).max(Location::from(b.beginning));
-        let new_end = Location::from(a.end).min(Location::from(b.end));
-        if new_beginning < new_end {
-            Some((new_beginning, new_end))
-        } else {
-            None
-        }
-    }
+    use crate::tests::FlashImage;
     struct Buffer {}
     impl FlashAlign for Buffer {
         fn erasable_block_size(&self) -> usize {
@@ -102,9 +883,9 @@ mod allocator_tests {
         let efh_range = buf.efh_range();
         let a = allocator.take_at_least(42).unwrap();
         let b = allocator.take_at_least(100).unwrap();
-        assert!(intersect(&a, &b).is_none());
-        assert!(intersect(&a, &efh_range).is_none());
-        assert!(intersect(&b, &efh_range).is_none());
+        assert!(a.intersect(&b).is_none());
+        assert!(a.intersect(&efh_range).is_none());
+        assert!(b.intersect(&efh_range).is_none());
         assert!(Location::from(b.end) < 0x2_0000);
     }
 
@@ -115,9 +896,9 @@ mod allocator_tests {
         let efh_range = buf.efh_range();
         let a = allocator.take_at_least(0x2_0000).unwrap();
         let b = allocator.take_at_least(100).unwrap();
-        assert!(intersect(&a, &b).is_none());
-        assert!(intersect(&a, &efh_range).is_none());
-        assert!(intersect(&b, &efh_range).is_none());
+        assert!(a.intersect(&b).is_none());
+        assert!(a.intersect(&efh_range).is_none());
+        assert!(b.intersect(&efh_range).is_none());
         assert!(Location::from(b.end) < 0x4_0000);
     }
 
@@ -128,10 +909,694 @@ mod allocator_tests {
         let efh_range = buf.efh_range();
         let a = allocator.take_at_least(0x1_fff8).unwrap();
         let b = allocator.take_at_least(100).unwrap();
-        assert!(intersect(&a, &b).is_none());
-        assert!(intersect(&a, &efh_range).is_none());
-        assert!(intersect(&b, &efh_range).is_none());
+        assert!(a.intersect(&b).is_none());
+        assert!(a.intersect(&efh_range).is_none());
+        assert!(b.intersect(&efh_range).is_none());
         assert!(Location::from(b.end) < 0x4_0000);
         assert!(Location::from(b.beginning) > 0x2_0000);
     }
+
+    #[test]
+    fn test_allocator_overflow() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        assert!(allocator.take_at_least(usize::MAX).is_none());
+        assert!(allocator.take_at_least(usize::MAX - 3).is_none());
+        assert!(allocator.take_at_least_ascending(usize::MAX).is_none());
+        // Fits into usize when rounded up, but not into a Location
+        assert!(allocator.take_at_least(Location::MAX as usize - 2).is_none());
+        assert!(allocator.take_at_least(Location::MAX as usize + 1).is_none());
+        let mut allocator = Capped::new(allocator, usize::MAX);
+        assert!(allocator.take_at_least(usize::MAX).is_none());
+        // Nothing was consumed.
+        let allocator = allocator.into_inner();
+        assert_eq!(allocator.max_contiguous_capacity(), 0x2_0000);
+    }
+
+    #[test]
+    fn test_multi_arena_allocator() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x4_0000).unwrap();
+        let mut allocator = MultiArenaAllocator::new(
+            [(0x1000, 0x200), (0x2_0000, 0x200), (0x3_0000, 0x1_0000)],
+            ErasableRange::new(beginning, end),
+        )
+        .unwrap();
+        assert_eq!(allocator.overhead(), 0x1_0400);
+        assert_eq!(allocator.max_contiguous_capacity(), 0x1_ee00);
+        let a = allocator.take_at_least(0x1000).unwrap();
+        let b = allocator.take_at_least(0x1000).unwrap();
+        let c = allocator.take_at_least(0x1_0000).unwrap();
+        let d = allocator.take_at_least(0xe000).unwrap();
+        let beginnings = [&a, &b, &c, &d].map(|x| Location::from(x.beginning));
+        assert_eq!(beginnings, [0, 0x1200, 0x2200, 0x2_0200]);
+        for range in [&a, &b, &c, &d] {
+            for reserved in allocator.reserved() {
+                assert!(range.intersect(reserved).is_none());
+            }
+        }
+        // The top free range is empty.
+        assert!(allocator.take_at_least(0x1_0000).is_none());
+        assert_eq!(allocator.max_contiguous_capacity(), 0xde00);
+    }
+
+    #[test]
+    fn test_multi_arena_allocator_invalid() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x4_0000).unwrap();
+        assert!(matches!(
+            MultiArenaAllocator::new(
+                [(0x2_0000, 0x200), (0x1000, 0x200)],
+                ErasableRange::new(beginning, end),
+            ),
+            Err(Error::Programmer)
+        ));
+        assert!(matches!(
+            MultiArenaAllocator::new(
+                [(0x1000, 0x200), (0x1100, 0x200)],
+                ErasableRange::new(beginning, end),
+            ),
+            Err(Error::Programmer)
+        ));
+        assert!(matches!(
+            MultiArenaAllocator::new(
+                [(0x1001, 0x200)],
+                ErasableRange::new(beginning, end),
+            ),
+            Err(Error::Alignment)
+        ));
+        assert!(matches!(
+            MultiArenaAllocator::new(
+                [(0x3_0000, 0x2_0000)],
+                ErasableRange::new(beginning, end),
+            ),
+            Err(Error::Size)
+        ));
+    }
+
+    #[test]
+    fn test_alloc_up_to() {
+        let buf = Buffer {};
+        let mut arena = buf.allocator();
+        // Also makes sure that FlashAllocate stays object-safe.
+        let allocator: &mut dyn FlashAllocate = &mut arena;
+        // More than available
+        let a = allocator.alloc_up_to(0x10_0000).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        assert_eq!(a.capacity(), 0x2_0000);
+        // Less than available
+        let b = allocator.alloc_up_to(0x101).unwrap();
+        assert_eq!(Location::from(b.beginning), 0x2_0200);
+        assert_eq!(b.capacity(), 0x104);
+        // Exactly what is available
+        let c = allocator.alloc_up_to(0x1_fcfc).unwrap();
+        assert_eq!(Location::from(c.beginning), 0x2_0304);
+        assert_eq!(Location::from(c.end), 0x4_0000);
+        assert_eq!(allocator.max_contiguous_capacity(), 0);
+        assert!(allocator.alloc_up_to(0x1000).is_none());
+        assert!(allocator.alloc_up_to(0).is_none());
+    }
+
+    #[test]
+    fn test_allocator_free() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let capacity = allocator.max_contiguous_capacity();
+        let a = allocator.take_at_least(0x1000).unwrap();
+        let b = allocator.take_at_least(0x2_0000 - 0x1000).unwrap();
+        let c = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(c.beginning), 0x2_0200);
+        assert_eq!(allocator.max_contiguous_capacity(), 0x1_fd00);
+
+        // Out of order
+        allocator.free(a).unwrap();
+        assert_eq!(allocator.max_contiguous_capacity(), 0x1_fd00);
+        let a = allocator.take_at_least(0x800).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        allocator.free(b).unwrap();
+        let d = allocator.take_at_least(0x1_f000).unwrap();
+        assert_eq!(Location::from(d.beginning), 0x800);
+        allocator.free(a).unwrap();
+        allocator.free(d).unwrap();
+        assert_eq!(allocator.max_contiguous_capacity(), capacity);
+        allocator.free(c).unwrap();
+
+        let a = allocator.take_at_least(capacity).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        let b = allocator.take_at_least(0x1_fe00).unwrap();
+        assert_eq!(Location::from(b.beginning), 0x2_0200);
+        assert_eq!(allocator.max_contiguous_capacity(), 0);
+    }
+
+    #[test]
+    fn test_allocator_entries() {
+        let buf = Buffer {};
+        // Nothing given back: no entries needed
+        let mut allocator = buf.allocator();
+        for i in 0..4 * DEFAULT_ENTRIES {
+            let range = allocator.take_at_least(4).unwrap();
+            assert_eq!(Location::from(range.beginning), 4 * i as Location);
+        }
+
+        let beginning = buf.erasable_location(0).unwrap();
+        let arena =
+            ErasableRange::new(beginning, beginning.advance(0x100).unwrap());
+        let mut allocator =
+            MultiArenaAllocator::<0, 2>::with_entries([], arena).unwrap();
+        let a = allocator.take_at_least(4).unwrap();
+        let b = allocator.take_at_least(4).unwrap();
+        // Both entries are in use: still handed out, but can't be given back
+        let c = allocator.take_at_least(4).unwrap();
+        assert_eq!(Location::from(c.beginning), 8);
+        assert!(matches!(allocator.free(c), Err(Error::Programmer)));
+        allocator.free(a).unwrap();
+        // Takes the entry of the free range
+        let d = allocator.take_at_least(4).unwrap();
+        assert_eq!(Location::from(d.beginning), 0);
+        allocator.free(d).unwrap();
+        allocator.free(b).unwrap();
+        assert_eq!(allocator.max_contiguous_capacity(), 0xf4);
+
+        // Without entries, skipped space is lost.
+        let arena =
+            ErasableRange::new(beginning, beginning.advance(0x100).unwrap());
+        let mut allocator =
+            MultiArenaAllocator::<0, 0>::with_entries([], arena).unwrap();
+        let a = allocator.take_at_least(4).unwrap();
+        assert!(matches!(allocator.free(a), Err(Error::Programmer)));
+        let b = allocator.take_at_least_avoiding(4, block::Size::B4K);
+        assert_eq!(Location::from(b.unwrap().beginning), 4);
+        let c = allocator.take_aligned(4, block::Size::B4K);
+        assert!(matches!(c, Err(Error::Size)));
+        assert_eq!(allocator.max_contiguous_capacity(), 0xf8);
+    }
+
+    #[test]
+    fn test_allocator_free_unsupported() {
+        // Only implements what FlashAllocate requires
+        struct Bump(ErasableRange);
+        impl FlashAllocate for Bump {
+            fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+                self.0.take_at_least(size)
+            }
+            fn max_contiguous_capacity(&self) -> usize {
+                self.0.capacity()
+            }
+        }
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance(0x100).unwrap();
+        let mut allocator = Bump(ErasableRange::new(beginning, end));
+        let a = allocator.take_at_least(0x10).unwrap();
+        assert!(matches!(allocator.free(a), Err(Error::Programmer)));
+    }
+
+    #[test]
+    fn test_capped_allocator_without_free() {
+        struct Bump(ErasableRange);
+        impl FlashAlign for Bump {
+            fn erasable_block_size(&self) -> usize {
+                self.0.beginning.erasable_block_size
+            }
+        }
+        impl FlashAllocate for Bump {
+            fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+                self.0.take_at_least(size)
+            }
+            fn max_contiguous_capacity(&self) -> usize {
+                self.0.capacity()
+            }
+        }
+        let buf = Buffer {};
+        let erasable_block_size = buf.erasable_block_size();
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance(4 * erasable_block_size).unwrap();
+        let mut allocator = Capped::new(
+            Bump(ErasableRange::new(beginning, end)),
+            erasable_block_size,
+        );
+        assert!(allocator.take_at_least(erasable_block_size + 1).is_none());
+        assert!(matches!(
+            allocator.take_aligned(erasable_block_size + 1, block::Size::B4K),
+            Err(Error::Size)
+        ));
+        // Nothing was taken, although Bump can't give anything back.
+        let a = allocator.take_at_least(1).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        let allocator = allocator.into_inner();
+        assert_eq!(
+            allocator.max_contiguous_capacity(),
+            3 * erasable_block_size
+        );
+    }
+
+    #[test]
+    fn test_allocator_free_invalid() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let range = |beginning, size| {
+            let beginning = buf.erasable_location(beginning).unwrap();
+            ErasableRange::new(beginning, beginning.advance(size).unwrap())
+        };
+        // Never taken
+        assert!(matches!(
+            allocator.free(range(0, 0x100)),
+            Err(Error::Programmer)
+        ));
+        let a = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        // Free space
+        assert!(matches!(
+            allocator.free(range(0x100, 0x100)),
+            Err(Error::Programmer)
+        ));
+        // Part of a range that is still taken
+        assert!(matches!(
+            allocator.free(range(0x80, 0x80)),
+            Err(Error::Programmer)
+        ));
+        assert!(matches!(
+            allocator.free(range(0, 0x80)),
+            Err(Error::Programmer)
+        ));
+        // More than was taken
+        assert!(matches!(
+            allocator.free(range(0, 0x200)),
+            Err(Error::Programmer)
+        ));
+        // EFH
+        assert!(matches!(
+            allocator.free(buf.efh_range()),
+            Err(Error::Programmer)
+        ));
+        assert!(matches!(
+            allocator.free(range(0x1_ff00, 0x200)),
+            Err(Error::Programmer)
+        ));
+        // Outside arena
+        assert!(matches!(
+            allocator.free(range(0x4_0000, 0x100)),
+            Err(Error::Programmer)
+        ));
+        allocator.free(a).unwrap();
+        // Twice
+        assert!(matches!(
+            allocator.free(range(0, 0x100)),
+            Err(Error::Programmer)
+        ));
+    }
+
+    #[test]
+    fn test_allocator_avoiding_boundaries() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let b64k = block::Size::B64K;
+        let a = allocator.take_at_least_avoiding(0x100, b64k).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        // Would cross 0x1_0000 at 0x100
+        let b = allocator.take_at_least_avoiding(0x1_0000, b64k).unwrap();
+        assert_eq!(Location::from(b.beginning), 0x1_0000);
+        assert_eq!(Location::from(b.end), 0x2_0000);
+        // The skipped space stays free.
+        let c = allocator.take_at_least_avoiding(0x1000, b64k).unwrap();
+        assert_eq!(Location::from(c.beginning), 0x100);
+        assert!(!c.crosses_boundary(b64k));
+        // Would cross 0x3_0000 at 0x2_0200
+        let d = allocator.take_at_least_avoiding(0x1_0000, b64k).unwrap();
+        assert_eq!(Location::from(d.beginning), 0x3_0000);
+        assert_eq!(Location::from(d.end), 0x4_0000);
+        let e = allocator.take_at_least_avoiding(0xe000, b64k).unwrap();
+        assert_eq!(Location::from(e.beginning), 0x1100);
+        let f = allocator.take_at_least_avoiding(0xfe00, b64k).unwrap();
+        assert_eq!(Location::from(f.beginning), 0x2_0200);
+        assert_eq!(allocator.max_contiguous_capacity(), 0xf00);
+        assert!(allocator.take_at_least_avoiding(0x1000, b64k).is_none());
+    }
+
+    #[test]
+    fn test_virtual_arena() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        allocator.take_at_least(0x1000).unwrap();
+        let arena = allocator.virtual_arena();
+        assert_eq!(arena.size(), 0x1_f000 + 0x1_fe00);
+        assert_eq!(arena.location(0), Some(0x1000));
+        assert_eq!(arena.location(0x1_efff), Some(0x1_ffff));
+        // Past the first free range, skipping the EFH
+        assert_eq!(arena.location(0x1_f000), Some(0x2_0200));
+        assert_eq!(arena.location(0x1_f123), Some(0x2_0323));
+        assert_eq!(arena.location(arena.size() - 1), Some(0x3_ffff));
+        assert_eq!(arena.location(arena.size()), None);
+        assert_eq!(arena.location(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_best_fit_allocator() {
+        let buf = Buffer {};
+        let arena = || {
+            let beginning = buf.erasable_location(0).unwrap();
+            let end = beginning.advance_at_least(0x3200).unwrap();
+            // Free ranges: [0, 0x2000) and [0x2200, 0x3200)
+            MultiArenaAllocator::new(
+                [(0x2000, 0x200)],
+                ErasableRange::new(beginning, end),
+            )
+            .unwrap()
+        };
+        let mut first_fit = arena();
+        first_fit.take_at_least(0x1000).unwrap();
+        assert!(first_fit.take_at_least(0x2000).is_none());
+
+        let mut best_fit = BestFitAllocator::new(arena());
+        let a = best_fit.take_at_least(0xffd).unwrap();
+        assert_eq!(Location::from(a.beginning), 0x2200);
+        let b = best_fit.take_at_least(0x2000).unwrap();
+        assert_eq!(Location::from(b.beginning), 0);
+        assert_eq!(best_fit.max_contiguous_capacity(), 0);
+        assert!(best_fit.take_at_least(4).is_none());
+        assert!(best_fit.take_at_least(0).is_some());
+    }
+
+    #[test]
+    fn test_best_fit_allocator_tie() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x3000).unwrap();
+        let arenas = MultiArenaAllocator::new(
+            [(0x1000, 0x1000)],
+            ErasableRange::new(beginning, end),
+        )
+        .unwrap();
+        let mut allocator = BestFitAllocator::new(arenas);
+        let a = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        let b = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(b.beginning), 0x100);
+    }
+
+    #[test]
+    fn test_capped_allocator() {
+        let buf = Buffer {};
+        let mut allocator = Capped::new(buf.allocator(), 0x1000);
+        assert_eq!(allocator.max_contiguous_capacity(), 0x1000);
+        let a = allocator.take_at_least(0xffd).unwrap();
+        assert_eq!(a.capacity(), 0x1000);
+        let b = allocator.take_at_least(0x1000).unwrap();
+        assert_eq!(b.capacity(), 0x1000);
+        assert!(a.intersect(&b).is_none());
+        assert!(allocator.take_at_least(0x1001).is_none());
+        // The refused request did not consume anything.
+        let allocator = allocator.into_inner();
+        assert_eq!(allocator.max_contiguous_capacity(), 0x2_0000 - 0x200);
+    }
+    #[test]
+    fn test_allocator_take_aligned() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let a = allocator.take_at_least(0x10).unwrap();
+        let b = allocator.take_aligned(0x10, block::Size::B4K).unwrap();
+        assert_eq!(Location::from(b.beginning), 0x1000);
+        assert_eq!(b.capacity(), 0x10);
+        // The space in front of B stays free.
+        let c = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(c.beginning), Location::from(a.end));
+        assert!(matches!(
+            allocator.take_aligned(0x2_0000, block::Size::B64K),
+            Err(Error::Size)
+        ));
+    }
+    #[test]
+    fn test_capped_allocator_rounding() {
+        let buf = Buffer {};
+        let mut allocator = Capped::new(buf.allocator(), 0x1002);
+        assert_eq!(allocator.max_contiguous_capacity(), 0x1000);
+        // A request within the cap that is rounded up past it is refused
+        // up front.
+        assert!(allocator.take_at_least(0x1001).is_none());
+        assert!(matches!(
+            allocator.take_aligned(0x1001, block::Size::B4K),
+            Err(Error::Size)
+        ));
+        let a = allocator.take_at_least(0x1000).unwrap();
+        assert_eq!(a.capacity(), 0x1000);
+        let allocator = allocator.into_inner();
+        let free: usize =
+            allocator.free_ranges().map(|range| range.capacity()).sum();
+        assert_eq!(free, 0x4_0000 - 0x200 - 0x1000);
+    }
+
+    #[test]
+    fn test_allocator_overhead() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x4_0000).unwrap();
+        let allocator = buf.allocator();
+        assert_eq!(allocator.overhead(), 0x200);
+        let total: usize =
+            allocator.arenas.free_ranges().map(|range| range.capacity()).sum();
+        assert_eq!(total + allocator.overhead(), 0x4_0000);
+        let allocator = ArenaFlashAllocator::new(
+            0x2_0000,
+            0x1fd,
+            ErasableRange::new(beginning, end),
+        )
+        .unwrap();
+        assert_eq!(allocator.overhead(), 0x200);
+    }
+
+    #[test]
+    fn test_allocator_ascending() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let a = allocator.take_at_least_ascending(0x1_0000).unwrap();
+        let b = allocator.take_at_least_ascending(0x1_8000).unwrap();
+        let c = allocator.take_at_least_ascending(0x100).unwrap();
+        let d = allocator.take_at_least_ascending(0x10).unwrap();
+        let beginnings = [&a, &b, &c, &d].map(|x| Location::from(x.beginning));
+        assert_eq!(beginnings, [0, 0x2_0200, 0x3_8200, 0x3_8300]);
+        // The gap below is still available to take_at_least.
+        let e = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(e.beginning), 0x1_0000);
+    }
+
+    #[test]
+    fn test_allocator_into_ranges() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        allocator.take_at_least(0x100).unwrap();
+        allocator.take_at_least(0x1_ff00).unwrap();
+        allocator.take_at_least(0x1000).unwrap();
+        let (efh_range, [a, b]) = allocator.into_ranges();
+        assert_eq!(Location::from(efh_range.beginning), 0x2_0000);
+        assert_eq!(efh_range.capacity(), 0x200);
+        assert_eq!(a.capacity(), 0);
+        assert_eq!(Location::from(b.beginning), 0x2_1200);
+        assert_eq!(Location::from(b.end), 0x4_0000);
+    }
+
+    #[test]
+    fn test_allocator_efh_from_end() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x4_0000).unwrap();
+        let mut allocator = ArenaFlashAllocator::new_efh_from_end(
+            0x1000,
+            0x200,
+            ErasableRange::new(beginning, end),
+        )
+        .unwrap();
+        let efh_range = allocator.efh_range();
+        assert_eq!(Location::from(efh_range.beginning), 0x3_f000);
+        assert_eq!(Location::from(efh_range.end), 0x3_f200);
+        let efh_range = ErasableRange::new(efh_range.beginning, efh_range.end);
+        let a = allocator.take_at_least(0x3_f000).unwrap();
+        let b = allocator.take_at_least(0xe00).unwrap();
+        assert!(a.intersect(&efh_range).is_none());
+        assert!(b.intersect(&efh_range).is_none());
+        assert_eq!(Location::from(b.end), 0x4_0000);
+        assert!(allocator.take_at_least(4).is_none());
+    }
+
+    #[test]
+    fn test_allocator_efh_from_end_too_far() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x1000).unwrap();
+        assert!(matches!(
+            ArenaFlashAllocator::new_efh_from_end(
+                0x2000,
+                0x200,
+                ErasableRange::new(beginning, end),
+            ),
+            Err(Error::Size)
+        ));
+        assert!(matches!(
+            ArenaFlashAllocator::new_efh_from_end(
+                0x201,
+                0x200,
+                ErasableRange::new(beginning, end),
+            ),
+            Err(Error::Alignment)
+        ));
+    }
+
+    #[test]
+    fn test_allocator_max_capacity() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance_at_least(0x4_0000).unwrap();
+        let allocator = ArenaFlashAllocator::with_max_capacity(
+            0x2_0000,
+            0x200,
+            ErasableRange::new(beginning, end),
+            0x4_0000,
+        )
+        .unwrap();
+        assert_eq!(allocator.max_contiguous_capacity(), 0x2_0000);
+        assert!(matches!(
+            ArenaFlashAllocator::with_max_capacity(
+                0x2_0000,
+                0x200,
+                ErasableRange::new(beginning, end),
+                0x3_ffff,
+            ),
+            Err(Error::Size)
+        ));
+    }
+
+    #[test]
+    fn test_alloc_and_erase() {
+        extern crate std;
+        let mut storage = std::vec![0u8; 0x4_0000];
+        let flash = FlashImage::with_geometry(&mut storage, 4, &[]);
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let a = allocator.alloc_and_erase(&flash, 0x1e).unwrap();
+        assert_eq!(a.capacity(), 0x20);
+        assert_eq!(flash.erases.borrow().len(), 8);
+        assert!(flash.first_erased_block(&a).unwrap().is_some());
+        let mut buf = [0u8; 0x21];
+        flash.read_exact(a.beginning.into(), &mut buf).unwrap();
+        assert!(buf[..0x20].iter().all(|&x| x == 0xff));
+        assert_eq!(buf[0x20], 0);
+        let b = allocator.take_at_least(0x10).unwrap();
+        assert!(a.intersect(&b).is_none());
+        assert!(matches!(
+            allocator.alloc_and_erase(&flash, 0x10_0000),
+            Err(Error::Size)
+        ));
+        assert_eq!(flash.erases.borrow().len(), 8);
+    }
+
+    #[test]
+    fn sub_allocator() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let parent = allocator.take_at_least(0x100).unwrap();
+        let (beginning, end) =
+            (Location::from(parent.beginning), Location::from(parent.end));
+        let mut sub = SubAllocator::new(parent);
+        let directory = sub.take(0x12).unwrap();
+        let entries = sub.take(0x40).unwrap();
+        assert_eq!(Location::from(directory.beginning), beginning);
+        assert_eq!(directory.capacity(), 0x14);
+        assert_eq!(
+            Location::from(entries.beginning),
+            Location::from(directory.end)
+        );
+        assert!(Location::from(entries.end) <= end);
+        assert_eq!(sub.remaining(), 0x100 - 0x14 - 0x40);
+        assert!(sub.take(0x100).is_none());
+        assert_eq!(sub.remaining(), 0x100 - 0x14 - 0x40);
+        let rest = sub.into_remainder();
+        assert_eq!(Location::from(rest.beginning), Location::from(entries.end));
+        assert_eq!(Location::from(rest.end), end);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_named_allocator() {
+        let buf = Buffer {};
+        let mut allocator = NamedAllocator::new(buf.allocator());
+        let bios = allocator.alloc_named("bios", 0x1000).unwrap();
+        assert_eq!(bios.capacity(), 0x1000);
+        allocator.alloc_named("psp", 42).unwrap();
+        let bios = allocator.get("bios").unwrap();
+        let psp = allocator.get("psp").unwrap();
+        assert!(bios.intersect(psp).is_none());
+        assert_eq!(psp.capacity(), 44);
+        assert!(allocator.get("efh").is_none());
+        let names: Vec<&str> =
+            allocator.entries().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["bios", "psp"]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_named_allocator_duplicate() {
+        let buf = Buffer {};
+        let mut allocator = NamedAllocator::new(buf.allocator());
+        allocator.alloc_named("bios", 0x1000).unwrap();
+        assert!(matches!(
+            allocator.alloc_named("bios", 0x1000),
+            Err(Error::Programmer)
+        ));
+        assert!(matches!(
+            allocator.alloc_named("huge", 0x10_0000),
+            Err(Error::Size)
+        ));
+        assert_eq!(allocator.entries().len(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_diff_layouts() {
+        let buf = Buffer {};
+        let range = |beginning: Location, end: Location| {
+            ErasableRange::new(
+                buf.erasable_location(beginning).unwrap(),
+                buf.erasable_location(end).unwrap(),
+            )
+        };
+        let old = [
+            ("bios".to_string(), range(0, 0x100)),
+            ("psp".to_string(), range(0x100, 0x200)),
+            ("apcb".to_string(), range(0x200, 0x300)),
+            ("same".to_string(), range(0x300, 0x400)),
+        ];
+        let new = [
+            ("same".to_string(), range(0x300, 0x400)),
+            ("bios".to_string(), range(0x1000, 0x1100)),
+            ("psp".to_string(), range(0x100, 0x280)),
+            ("abl".to_string(), range(0x2000, 0x2010)),
+        ];
+        assert_eq!(diff_layouts(&old, &old), []);
+        assert_eq!(
+            diff_layouts(&old, &new),
+            [
+                LayoutChange::Moved {
+                    name: "bios".into(),
+                    old_beginning: 0,
+                    new_beginning: 0x1000
+                },
+                LayoutChange::Resized {
+                    name: "psp".into(),
+                    old_size: 0x100,
+                    new_size: 0x180
+                },
+                LayoutChange::Removed {
+                    name: "apcb".into(),
+                    beginning: 0x200,
+                    size: 0x100
+                },
+                LayoutChange::Added {
+                    name: "abl".into(),
+                    beginning: 0x2000,
+                    size: 0x10
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,402 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableLocation, Error, FlashAlign, Location, Result};
+
+/// Erasable block size.
+/// Note: The discriminant is the size in Byte.
+/// Note: Variants are in ascending order, so comparison is by size.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size {
+    B4K = 0x1000,
+    B8K = 0x2000,
+    B12K = 0x3000,
+    B16K = 0x4000,
+    B20K = 0x5000,
+    B24K = 0x6000,
+    B28K = 0x7000,
+    B32K = 0x8000,
+    B36K = 0x9000,
+    B40K = 0xa000,
+    B44K = 0xb000,
+    B48K = 0xc000,
+    B52K = 0xd000,
+    B56K = 0xe000,
+    B60K = 0xf000,
+    B64K = 0x1_0000,
+}
+
+// The discriminants have to be the size in Byte, since `as usize` is
+// used for sizing.
+const _: () = assert!(Size::B4K as usize == 4 * 1024);
+const _: () = assert!(Size::B8K as usize == 8 * 1024);
+const _: () = assert!(Size::B12K as usize == 12 * 1024);
+const _: () = assert!(Size::B16K as usize == 16 * 1024);
+const _: () = assert!(Size::B20K as usize == 20 * 1024);
+const _: () = assert!(Size::B24K as usize == 24 * 1024);
+const _: () = assert!(Size::B28K as usize == 28 * 1024);
+const _: () = assert!(Size::B32K as usize == 32 * 1024);
+const _: () = assert!(Size::B36K as usize == 36 * 1024);
+const _: () = assert!(Size::B40K as usize == 40 * 1024);
+const _: () = assert!(Size::B44K as usize == 44 * 1024);
+const _: () = assert!(Size::B48K as usize == 48 * 1024);
+const _: () = assert!(Size::B52K as usize == 52 * 1024);
+const _: () = assert!(Size::B56K as usize == 56 * 1024);
+const _: () = assert!(Size::B60K as usize == 60 * 1024);
+const _: () = assert!(Size::B64K as usize == 64 * 1024);
+
+impl Size {
+    /// All the variants, in ascending order.
+    pub const ALL: [Self; 16] = [
+        Self::B4K,
+        Self::B8K,
+        Self::B12K,
+        Self::B16K,
+        Self::B20K,
+        Self::B24K,
+        Self::B28K,
+        Self::B32K,
+        Self::B36K,
+        Self::B40K,
+        Self::B44K,
+        Self::B48K,
+        Self::B52K,
+        Self::B56K,
+        Self::B60K,
+        Self::B64K,
+    ];
+    /// Iterates over all the variants, in ascending order.
+    pub fn all() -> impl Iterator<Item = Self> {
+        Self::ALL.iter().copied()
+    }
+    /// Converts the 4-bit block size code used by AMD (for example in the
+    /// EFH) into a Size.
+    /// Code 0 means 64 KiB, every other code N means N * 4 KiB.
+    pub const fn try_from_block_size(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::B64K),
+            1 => Some(Self::B4K),
+            2 => Some(Self::B8K),
+            3 => Some(Self::B12K),
+            4 => Some(Self::B16K),
+            5 => Some(Self::B20K),
+            6 => Some(Self::B24K),
+            7 => Some(Self::B28K),
+            8 => Some(Self::B32K),
+            9 => Some(Self::B36K),
+            10 => Some(Self::B40K),
+            11 => Some(Self::B44K),
+            12 => Some(Self::B48K),
+            13 => Some(Self::B52K),
+            14 => Some(Self::B56K),
+            15 => Some(Self::B60K),
+            _ => None,
+        }
+    }
+    /// Converts the Size into the 4-bit block size code used by AMD (the
+    /// inverse of try_from_block_size).
+    pub const fn to_block_size_code(self) -> u32 {
+        match self {
+            Self::B64K => 0,
+            Self::B4K => 1,
+            Self::B8K => 2,
+            Self::B12K => 3,
+            Self::B16K => 4,
+            Self::B20K => 5,
+            Self::B24K => 6,
+            Self::B28K => 7,
+            Self::B32K => 8,
+            Self::B36K => 9,
+            Self::B40K => 10,
+            Self::B44K => 11,
+            Self::B48K => 12,
+            Self::B52K => 13,
+            Self::B56K => 14,
+            Self::B60K => 15,
+        }
+    }
+    /// Returns the Size of N Byte, if there is one.
+    pub const fn from_bytes(n: usize) -> Option<Self> {
+        match n {
+            0x1000 => Some(Self::B4K),
+            0x2000 => Some(Self::B8K),
+            0x3000 => Some(Self::B12K),
+            0x4000 => Some(Self::B16K),
+            0x5000 => Some(Self::B20K),
+            0x6000 => Some(Self::B24K),
+            0x7000 => Some(Self::B28K),
+            0x8000 => Some(Self::B32K),
+            0x9000 => Some(Self::B36K),
+            0xa000 => Some(Self::B40K),
+            0xb000 => Some(Self::B44K),
+            0xc000 => Some(Self::B48K),
+            0xd000 => Some(Self::B52K),
+            0xe000 => Some(Self::B56K),
+            0xf000 => Some(Self::B60K),
+            0x1_0000 => Some(Self::B64K),
+            _ => None,
+        }
+    }
+    /// Rounds N down to a multiple of the block size.
+    pub const fn align_down(self, n: usize) -> usize {
+        n - (n % self as usize)
+    }
+    /// Returns how many pages of size PAGE fit into one block of our size,
+    /// if that is a whole number.
+    pub const fn pages_of(self, page: Size) -> Option<usize> {
+        if self as usize % page as usize == 0 {
+            Some(self as usize / page as usize)
+        } else {
+            None
+        }
+    }
+    /// Returns whether FLASH uses our size as its erasable block size.
+    pub fn matches<F: FlashAlign + ?Sized>(self, flash: &F) -> bool {
+        flash.erasable_block_size() == self as usize
+    }
+    /// Like matches, but as a guard for use with `?`: returns Some(()) if
+    /// FLASH uses our size as its erasable block size, and None otherwise.
+    /// Note: Sizes are runtime values here, so this can't turn FLASH into a
+    /// type with a const block size; it only checks before such a
+    /// conversion.
+    pub fn as_const<F: FlashAlign + ?Sized>(self, flash: &F) -> Option<()> {
+        self.matches(flash).then_some(())
+    }
+    /// Returns whether LOCATION was created for our erasable block size.
+    pub fn matches_location(self, location: ErasableLocation) -> bool {
+        location.erasable_block_size() == self as usize
+    }
+    /// Checks whether LOCATION is on a block boundary.
+    /// Unlike FlashAlign, this also works for sizes that are not a power
+    /// of two.
+    pub const fn check_aligned(self, location: Location) -> Result<()> {
+        if location as usize % self as usize == 0 {
+            Ok(())
+        } else {
+            Err(Error::Alignment)
+        }
+    }
+}
+
+impl From<Size> for usize {
+    fn from(source: Size) -> Self {
+        source as usize
+    }
+}
+
+/// Formats the Size in KiB, for example "64K".
+impl core::fmt::Display for Size {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}K", *self as usize / 1024)
+    }
+}
+
+/// Error of parsing a Size from a string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseSizeError;
+
+impl core::fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("invalid block size (expected for example \"64K\")")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseSizeError {}
+
+/// Parses a Size in KiB, for example "64K" (like Display formats it).
+impl core::str::FromStr for Size {
+    type Err = ParseSizeError;
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        let kib = s
+            .strip_suffix('K')
+            .filter(|kib| kib.bytes().all(|x| x.is_ascii_digit()))
+            .and_then(|kib| kib.parse::<usize>().ok())
+            .ok_or(ParseSizeError)?;
+        kib.checked_mul(1024).and_then(Self::from_bytes).ok_or(ParseSizeError)
+    }
+}
+
+/// Erasable block geometry whose block size is only known at run time,
+/// for example because it was read from the EFH.
+#[derive(Clone, Copy, Debug)]
+pub struct Geometry {
+    size: Size,
+}
+
+impl Geometry {
+    pub fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl FlashAlign for Geometry {
+    fn erasable_block_size(&self) -> usize {
+        self.size.into()
+    }
+}
+
+/// Given the AMD block size CODE, returns a Geometry that can be used to
+/// construct erasable locations of that block size.
+/// Since FlashAlign requires a power of two, returns None for block sizes
+/// that are not a power of two (and for invalid codes).
+pub fn locations_for_code(code: u32) -> Option<Geometry> {
+    let size = Size::try_from_block_size(code)?;
+    usize::from(size).is_power_of_two().then_some(Geometry { size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_from_block_size() {
+        assert_eq!(Size::try_from_block_size(0), Some(Size::B64K));
+        for code in 1..16 {
+            let size = Size::try_from_block_size(code).unwrap();
+            assert_eq!(usize::from(size), code as usize * 0x1000);
+        }
+        assert_eq!(Size::try_from_block_size(16), None);
+    }
+
+    #[test]
+    fn all() {
+        assert_eq!(Size::all().count(), 16);
+        assert!(Size::ALL.windows(2).all(|w| w[0] < w[1]));
+        assert!(Size::ALL
+            .windows(2)
+            .all(|w| usize::from(w[0]) < usize::from(w[1])));
+        for code in 0..16 {
+            let size = Size::try_from_block_size(code).unwrap();
+            assert!(Size::all().any(|x| x == size));
+        }
+    }
+
+    #[test]
+    fn to_block_size_code() {
+        for size in Size::all() {
+            let code = size.to_block_size_code();
+            assert!(code < 16);
+            assert_eq!(Size::try_from_block_size(code), Some(size));
+        }
+        assert_eq!(Size::B64K.to_block_size_code(), 0);
+        assert_eq!(Size::B4K.to_block_size_code(), 1);
+    }
+
+    #[test]
+    fn from_bytes() {
+        assert_eq!(Size::from_bytes(4096), Some(Size::B4K));
+        assert_eq!(Size::from_bytes(0x3000), Some(Size::B12K));
+        assert_eq!(Size::from_bytes(65536), Some(Size::B64K));
+        assert_eq!(Size::from_bytes(1000), None);
+        assert_eq!(Size::from_bytes(0), None);
+        assert_eq!(Size::from_bytes(0x2_0000), None);
+        for size in Size::all() {
+            assert_eq!(Size::from_bytes(size.into()), Some(size));
+        }
+    }
+
+    #[test]
+    fn size_strings() {
+        extern crate std;
+        use std::string::ToString;
+        for size in Size::all() {
+            let s = size.to_string();
+            assert_eq!(s, std::format!("{}K", usize::from(size) / 1024));
+            assert_eq!(s.parse::<Size>(), Ok(size));
+        }
+        assert_eq!("4K".parse::<Size>(), Ok(Size::B4K));
+        assert_eq!("12K".parse::<Size>(), Ok(Size::B12K));
+        assert_eq!("64K".parse::<Size>(), Ok(Size::B64K));
+        for s in ["5K", "0K", "68K", "64", "K", "", "64k", "+4K", " 4K", "4KK"]
+        {
+            assert_eq!(s.parse::<Size>(), Err(ParseSizeError), "{s:?}");
+        }
+        assert_eq!(
+            "18446744073709551615K".parse::<Size>(),
+            Err(ParseSizeError)
+        );
+    }
+
+    #[test]
+    fn align_down() {
+        assert_eq!(Size::B4K.align_down(4097), 4096);
+        assert_eq!(Size::B4K.align_down(4096), 4096);
+        assert_eq!(Size::B4K.align_down(4095), 0);
+        assert_eq!(Size::B4K.align_down(0), 0);
+        assert_eq!(Size::B12K.align_down(0x7000), 0x6000);
+        assert_eq!(Size::B64K.align_down(usize::MAX), usize::MAX & !0xffff);
+    }
+
+    #[test]
+    fn pages_of() {
+        assert_eq!(Size::B64K.pages_of(Size::B4K), Some(16));
+        assert_eq!(Size::B64K.pages_of(Size::B64K), Some(1));
+        assert_eq!(Size::B48K.pages_of(Size::B12K), Some(4));
+        assert_eq!(Size::B4K.pages_of(Size::B64K), None);
+        assert_eq!(Size::B64K.pages_of(Size::B12K), None);
+    }
+
+    #[test]
+    fn matches() {
+        let geometry = locations_for_code(1).unwrap();
+        assert!(Size::B4K.matches(&geometry));
+        assert!(!Size::B64K.matches(&geometry));
+        let location = geometry.erasable_location(0x1000).unwrap();
+        assert!(Size::B4K.matches_location(location));
+        assert!(!Size::B8K.matches_location(location));
+    }
+
+    #[test]
+    fn as_const() {
+        let geometry = locations_for_code(1).unwrap();
+        let bytes = |size: Size| -> Option<usize> {
+            size.as_const(&geometry)?;
+            Some(usize::from(size))
+        };
+        assert_eq!(bytes(Size::B4K), Some(0x1000));
+        assert_eq!(bytes(Size::B64K), None);
+    }
+
+    #[test]
+    fn check_aligned() {
+        assert!(Size::B12K.check_aligned(0).is_ok());
+        assert!(Size::B12K.check_aligned(0x3000).is_ok());
+        assert!(Size::B12K.check_aligned(0x9000).is_ok());
+        assert!(matches!(
+            Size::B12K.check_aligned(0x4000),
+            Err(Error::Alignment)
+        ));
+        assert!(matches!(Size::B12K.check_aligned(1), Err(Error::Alignment)));
+    }
+
+    #[test]
+    fn ordering() {
+        assert!(Size::B4K < Size::B64K);
+        assert!(Size::B12K > Size::B8K);
+        let mut sizes = [Size::B64K, Size::B4K, Size::B32K, Size::B12K];
+        sizes.sort();
+        assert_eq!(sizes, [Size::B4K, Size::B12K, Size::B32K, Size::B64K]);
+        assert!(sizes
+            .windows(2)
+            .all(|w| usize::from(w[0]) < usize::from(w[1])));
+    }
+
+    #[test]
+    fn locations_for_b16k() {
+        let geometry = locations_for_code(4).unwrap();
+        assert_eq!(geometry.size(), Size::B16K);
+        let location = geometry.erasable_location(0x8000).unwrap();
+        assert_eq!(location.erasable_block_size(), 0x4000);
+        assert_eq!(Location::from(location), 0x8000);
+        assert!(geometry.erasable_location(0x9000).is_none());
+    }
+
+    #[test]
+    fn locations_for_non_power_of_two() {
+        assert!(locations_for_code(3).is_none());
+        assert!(locations_for_code(16).is_none());
+    }
+}
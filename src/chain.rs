@@ -0,0 +1,215 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableLocation, FlashAlign, FlashRead, FlashWrite, Location};
+use crate::{Error, Result};
+use core::convert::TryFrom;
+use core::mem::MaybeUninit;
+
+/// Presents two flash chips A and B as one address space.
+/// Locations [0, a_capacity) are on A, and locations
+/// [a_capacity, a_capacity + b_capacity) are on B.
+pub struct ChainFlash<A, B> {
+    a: A,
+    b: B,
+    a_capacity: usize,
+    b_capacity: usize,
+}
+
+impl<A: FlashRead + FlashAlign, B: FlashRead + FlashAlign> ChainFlash<A, B> {
+    /// Both A and B have to report their capacity, and have to have the
+    /// same erasable_block_size().
+    /// The capacity of A has to be a multiple of erasable_block_size(),
+    /// so that no erasable block straddles both chips.
+    pub fn new(a: A, b: B) -> Result<Self> {
+        let a_capacity = a.capacity().ok_or(Error::Programmer)?;
+        let b_capacity = b.capacity().ok_or(Error::Programmer)?;
+        let total =
+            a_capacity.checked_add(b_capacity).ok_or(Error::Overflow)?;
+        Location::try_from(total)?;
+        if a.erasable_block_size() != b.erasable_block_size()
+            || !a.is_aligned(a_capacity as Location)
+        {
+            return Err(Error::Alignment);
+        }
+        Ok(Self { a, b, a_capacity, b_capacity })
+    }
+}
+
+impl<A, B> ChainFlash<A, B> {
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+    /// If LOCATION is on B, returns the corresponding location on B.
+    fn b_location(&self, location: Location) -> Option<Location> {
+        (location as usize)
+            .checked_sub(self.a_capacity)
+            .map(|location| location as Location)
+    }
+}
+
+impl<A: FlashRead, B: FlashRead> FlashRead for ChainFlash<A, B> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        let end = (beginning as usize)
+            .checked_add(buffer.len())
+            .filter(|&end| end <= self.a_capacity + self.b_capacity)
+            .ok_or(Error::OutOfBounds { location: beginning })?;
+        match self.b_location(beginning) {
+            Some(beginning) => self.b.read_exact(beginning, buffer),
+            None if end <= self.a_capacity => {
+                self.a.read_exact(beginning, buffer)
+            }
+            None => {
+                let (a_buffer, b_buffer) =
+                    buffer.split_at_mut(self.a_capacity - beginning as usize);
+                self.a.read_exact(beginning, a_buffer)?;
+                self.b.read_exact(0, b_buffer)
+            }
+        }
+    }
+    fn capacity(&self) -> Option<usize> {
+        Some(self.a_capacity + self.b_capacity)
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        let end = (beginning as usize)
+            .checked_add(buffer.len())
+            .filter(|&end| end <= self.a_capacity + self.b_capacity)
+            .ok_or(Error::OutOfBounds { location: beginning })?;
+        match self.b_location(beginning) {
+            Some(beginning) => self.b.read_exact_uninit(beginning, buffer),
+            None if end <= self.a_capacity => {
+                self.a.read_exact_uninit(beginning, buffer)
+            }
+            None => {
+                let (a_buffer, b_buffer) =
+                    buffer.split_at_mut(self.a_capacity - beginning as usize);
+                // Each part has to come back as itself, initialized.
+                let parts = [
+                    (a_buffer.as_ptr() as *const u8, a_buffer.len()),
+                    (b_buffer.as_ptr() as *const u8, b_buffer.len()),
+                ];
+                let a_data = self.a.read_exact_uninit(beginning, a_buffer)?;
+                let a_data = (a_data.as_ptr(), a_data.len());
+                let b_data = self.b.read_exact_uninit(0, b_buffer)?;
+                if parts != [a_data, (b_data.as_ptr(), b_data.len())] {
+                    return Err(Error::Programmer);
+                }
+                // SAFETY: A and B returned their parts of BUFFER as
+                // initialized.
+                Ok(unsafe { crate::assume_init(buffer) })
+            }
+        }
+    }
+}
+
+impl<A: FlashAlign, B> FlashAlign for ChainFlash<A, B> {
+    fn erasable_block_size(&self) -> usize {
+        self.a.erasable_block_size()
+    }
+}
+
+impl<A: FlashWrite, B: FlashWrite> FlashWrite for ChainFlash<A, B> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let location = self.location(location)?;
+        match self.b_location(location) {
+            Some(location) => self.b.erase_block(
+                self.b.erasable_location(location).ok_or(Error::Alignment)?,
+            ),
+            None => self.a.erase_block(
+                self.a.erasable_location(location).ok_or(Error::Alignment)?,
+            ),
+        }
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let location = self.location(location)?;
+        match self.b_location(location) {
+            Some(location) => self.b.erase_and_write_block(
+                self.b.erasable_location(location).ok_or(Error::Alignment)?,
+                buffer,
+            ),
+            None => self.a.erase_and_write_block(
+                self.a.erasable_location(location).ok_or(Error::Alignment)?,
+                buffer,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod chain_tests {
+    use super::*;
+    use crate::tests::FlashImage;
+    use crate::ErasableRange;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn chain_across_boundary() -> Result<()> {
+        let mut a_storage = [0u8; 16 * KIB];
+        let mut b_storage = [0u8; 16 * KIB];
+        let a = FlashImage::with_geometry(&mut a_storage[..], 4 * KIB, &[]);
+        let b = FlashImage::with_geometry(&mut b_storage[..], 4 * KIB, &[]);
+        let chain = ChainFlash::new(a, b)?;
+        assert_eq!(chain.capacity(), Some(32 * KIB));
+
+        let mut data = [0u8; 16 * KIB];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = (i / KIB) as u8 + 1;
+        }
+        let beginning = chain.erasable_location(8 * KIB as u32).unwrap();
+        chain.erase_and_write_blocks(beginning, &data)?;
+        let mut buf = [0u8; 16 * KIB];
+        chain.read_exact(8 * KIB as u32, &mut buf)?;
+        assert_eq!(buf, data);
+        let mut uninit = [MaybeUninit::uninit(); 16 * KIB];
+        assert_eq!(chain.read_exact_uninit(8 * KIB as u32, &mut uninit)?, data);
+
+        let end = beginning.advance(16 * KIB)?;
+        chain.erase_range(&ErasableRange::new(beginning, end))?;
+        chain.read_exact(8 * KIB as u32, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0xff));
+
+        let (a, b) = chain.into_inner();
+        assert_eq!(a.erases.borrow().len(), 2);
+        assert_eq!(b.erases.borrow().len(), 2);
+        let mut buf = [0u8; 8 * KIB];
+        a.read_exact(0, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0));
+        b.read_exact(8 * KIB as u32, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn chain_out_of_bounds() -> Result<()> {
+        let mut a_storage = [0u8; 16 * KIB];
+        let mut b_storage = [0u8; 16 * KIB];
+        let a = FlashImage::with_geometry(&mut a_storage[..], 4 * KIB, &[]);
+        let b = FlashImage::with_geometry(&mut b_storage[..], 4 * KIB, &[]);
+        let chain = ChainFlash::new(a, b)?;
+        let mut buf = [0u8; 2];
+        chain.read_exact(32 * KIB as u32 - 2, &mut buf)?;
+        assert!(matches!(
+            chain.read_exact(32 * KIB as u32 - 1, &mut buf),
+            Err(Error::OutOfBounds { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn chain_requires_same_block_size() {
+        let mut a_storage = [0u8; 16 * KIB];
+        let mut b_storage = [0u8; 16 * KIB];
+        let a = FlashImage::with_geometry(&mut a_storage[..], 4 * KIB, &[]);
+        let b = FlashImage::with_geometry(&mut b_storage[..], 8 * KIB, &[]);
+        assert!(matches!(ChainFlash::new(a, b), Err(Error::Alignment)));
+    }
+}
@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/// CRC-32 (IEEE 802.3) that can be fed incrementally.
+/// This is table-free, so it's small but not fast.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    const POLYNOMIAL: u32 = 0xedb8_8320; // reversed 0x04c1_1db7
+
+    pub const fn new() -> Self {
+        Self { state: 0xffff_ffff }
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        let mut state = self.state;
+        for &x in data {
+            state ^= u32::from(x);
+            for _ in 0..8 {
+                let mask = 0u32.wrapping_sub(state & 1);
+                state = (state >> 1) ^ (Self::POLYNOMIAL & mask);
+            }
+        }
+        self.state = state;
+    }
+    pub const fn finalize(self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 64 bit FNV-1a hash that can be fed incrementally.
+/// Note: This is meant for finding identical data (for deduplication),
+/// not for integrity checks, and certainly not against an adversary.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a64 {
+    state: u64,
+}
+
+impl Fnv1a64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub const fn new() -> Self {
+        Self { state: Self::OFFSET_BASIS }
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        let mut state = self.state;
+        for &x in data {
+            state ^= u64::from(x);
+            state = state.wrapping_mul(Self::PRIME);
+        }
+        self.state = state;
+    }
+    pub const fn finalize(self) -> u64 {
+        self.state
+    }
+}
+
+impl Default for Fnv1a64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod checksum_tests {
+    use super::*;
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finalize()
+    }
+
+    #[test]
+    fn crc32_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn crc32_incremental() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        for split in 0..data.len() {
+            let (a, b) = data.split_at(split);
+            let mut crc = Crc32::new();
+            crc.update(a);
+            crc.update(b);
+            assert_eq!(crc.finalize(), crc32(data));
+        }
+    }
+
+    #[test]
+    fn fnv1a64_known_values() {
+        let fnv1a64 = |data: &[u8]| {
+            let mut hash = Fnv1a64::new();
+            hash.update(data);
+            hash.finalize()
+        };
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a64(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::block;
+use crate::{
+    ErasableLocation, ErasableRange, FlashAlign, FlashRead, FlashWrite,
+    Location,
+};
+use crate::{Error, Result};
+use core::mem::MaybeUninit;
+
+/// Presents the flash chip D as if its erasable blocks were COARSE Byte
+/// (a multiple of the erasable block size of D), for layouts that only
+/// deal with bigger blocks.
+/// Erasing one of these blocks erases all the blocks of D in it.
+pub struct Coarsen<D> {
+    flash: D,
+    erasable_block_size: usize,
+}
+
+impl<D: FlashWrite> Coarsen<D> {
+    /// COARSE has to be a power of two and a multiple of the erasable
+    /// block size of FLASH (otherwise returns Error::Alignment).
+    pub fn new(flash: D, coarse: block::Size) -> Result<Self> {
+        let erasable_block_size = usize::from(coarse);
+        if !erasable_block_size.is_power_of_two()
+            || erasable_block_size % flash.erasable_block_size() != 0
+        {
+            return Err(Error::Alignment);
+        }
+        Ok(Self { flash, erasable_block_size })
+    }
+    pub fn into_inner(self) -> D {
+        self.flash
+    }
+    /// Returns the block of D at the same place as LOCATION.
+    fn fine_location(
+        &self,
+        location: ErasableLocation,
+    ) -> Result<ErasableLocation> {
+        self.flash
+            .erasable_location(self.location(location)?)
+            .ok_or(Error::Alignment)
+    }
+}
+
+impl<D: FlashRead> FlashRead for Coarsen<D> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        self.flash.read_exact(beginning, buffer)
+    }
+    fn capacity(&self) -> Option<usize> {
+        self.flash.capacity()
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        self.flash.read_exact_uninit(beginning, buffer)
+    }
+}
+
+impl<D: FlashAlign> FlashAlign for Coarsen<D> {
+    fn erasable_block_size(&self) -> usize {
+        self.erasable_block_size
+    }
+    fn preferred_read_chunk(&self) -> usize {
+        self.flash.preferred_read_chunk()
+    }
+}
+
+impl<D: FlashWrite> FlashWrite for Coarsen<D> {
+    fn lazy_erase(&self) -> bool {
+        self.flash.lazy_erase()
+    }
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let beginning = self.fine_location(location)?;
+        self.flash.erase_range(&ErasableRange::new(
+            beginning,
+            beginning.advance(self.erasable_block_size)?,
+        ))
+    }
+    /// Only the erase units of D that are coarser than our blocks.
+    fn coarse_erase_sizes(&self) -> &[usize] {
+        let sizes = self.flash.coarse_erase_sizes();
+        let count = sizes
+            .iter()
+            .take_while(|&&size| size > self.erasable_block_size)
+            .count();
+        &sizes[..count]
+    }
+    fn erase_coarse(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<()> {
+        self.flash.erase_coarse(self.fine_location(location)?, size)
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let beginning = self.fine_location(location)?;
+        self.flash.erase_and_write_blocks(beginning, buffer)?;
+        self.flash.erase_range(&ErasableRange::new(
+            beginning.advance_at_least(buffer.len())?,
+            beginning.advance(self.erasable_block_size)?,
+        ))
+    }
+    fn write_erased_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let mut location = self.fine_location(location)?;
+        for chunk in buffer.chunks(self.flash.erasable_block_size()) {
+            self.flash.write_erased_block(location, chunk)?;
+            location = location.advance_at_least(chunk.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod coarsen_tests {
+    use super::*;
+    use crate::tests::FlashImage;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn coarsen_erase() -> Result<()> {
+        let mut storage = [0u8; 192 * KIB];
+        let image = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let flash = Coarsen::new(&image, block::Size::B64K)?;
+        assert_eq!(flash.erasable_block_size(), 64 * KIB);
+        assert!(flash.erasable_location(4 * KIB as Location).is_none());
+        let location = flash.erasable_location(64 * KIB as Location).unwrap();
+        flash.erase_block(location)?;
+        assert_eq!(*image.erases.borrow(), [4 * KIB; 16]);
+
+        flash.erase_and_write_block(location, &[0x42; 6 * KIB])?;
+        let mut buf = [0u8; 66 * KIB];
+        image.read_exact(63 * KIB as Location, &mut buf)?;
+        assert_eq!(buf[0], 0);
+        assert!(buf[KIB..7 * KIB].iter().all(|&x| x == 0x42));
+        assert!(buf[7 * KIB..65 * KIB].iter().all(|&x| x == 0xff));
+        assert_eq!(buf[65 * KIB], 0);
+        assert!(matches!(
+            flash.erase_and_write_block(location, &[0; 64 * KIB + 1]),
+            Err(Error::Programmer)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn coarsen_alignment() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let image = FlashImage::with_geometry(&mut storage, 8 * KIB, &[]);
+        assert!(matches!(
+            Coarsen::new(&image, block::Size::B4K),
+            Err(Error::Alignment)
+        ));
+        // Multiple of the fine block size, but not a power of two.
+        let mut storage = [0u8; 48 * KIB];
+        let fine = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        assert!(matches!(
+            Coarsen::new(&fine, block::Size::B12K),
+            Err(Error::Alignment)
+        ));
+        let flash = Coarsen::new(&image, block::Size::B8K)?;
+        let location = image.erasable_location(0).unwrap();
+        assert!(flash.erase_block(location).is_ok());
+        let flash = Coarsen::new(&image, block::Size::B16K)?;
+        assert!(matches!(flash.erase_block(location), Err(Error::Alignment)));
+        Ok(())
+    }
+}
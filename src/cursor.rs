@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{Error, Result};
+use crate::{FlashRead, Location};
+use core::convert::TryFrom;
+use std::io;
+
+/// Reads the flash chip F as a std::io stream, for example to point
+/// existing parsers at a flash image.
+pub struct ReadCursor<'a, F> {
+    flash: &'a F,
+    capacity: u64,
+    position: u64,
+}
+
+impl<'a, F: FlashRead> ReadCursor<'a, F> {
+    /// FLASH has to report its capacity (otherwise returns
+    /// Error::Programmer), which is where the stream ends.
+    pub fn new(flash: &'a F) -> Result<Self> {
+        let capacity = flash.capacity().ok_or(Error::Programmer)?;
+        Ok(Self { flash, capacity: u64::try_from(capacity)?, position: 0 })
+    }
+    /// Current position in the stream, in Byte from the beginning of
+    /// the flash chip.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+fn io_error(error: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+impl<F: FlashRead> io::Read for ReadCursor<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remainder = self.capacity.saturating_sub(self.position);
+        let len =
+            usize::try_from(remainder).unwrap_or(usize::MAX).min(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        let beginning = Location::try_from(self.position)
+            .map_err(|e| io_error(e.into()))?;
+        self.flash.read_exact(beginning, &mut buf[..len]).map_err(io_error)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<F: FlashRead> io::Seek for ReadCursor<'_, F> {
+    /// Note: Seeking past the end is allowed; reads there return 0 Byte.
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            io::SeekFrom::End(offset) => (self.capacity, offset),
+            io::SeekFrom::Current(offset) => (self.position, offset),
+        };
+        let position = if offset < 0 {
+            base.checked_sub(offset.unsigned_abs())
+        } else {
+            base.checked_add(offset as u64)
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod cursor_tests {
+    use super::*;
+    use crate::mem::MemFlash;
+    use crate::{FlashAlign, FlashWrite};
+    use std::io::{Read, Seek, SeekFrom};
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn read_cursor_read_to_end() -> Result<()> {
+        let flash = MemFlash::blank(256 * KIB, 4 * KIB)?;
+        let mut data = vec![0u8; 130 * KIB];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        let beginning = flash.erasable_location(0x1_0000).unwrap();
+        flash.erase_and_write_blocks(beginning, &data)?;
+
+        let mut cursor = ReadCursor::new(&flash)?;
+        assert_eq!(cursor.seek(SeekFrom::Start(0x1_0000)).unwrap(), 0x1_0000);
+        let mut buf = Vec::new();
+        cursor.by_ref().take(130 * KIB as u64).read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(cursor.position(), 0x1_0000 + 130 * KIB as u64);
+
+        // Up to the end
+        let mut buf = Vec::new();
+        cursor.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 256 * KIB - 0x1_0000 - 130 * KIB);
+        assert!(buf.iter().all(|&x| x == 0xff));
+        assert_eq!(cursor.read(&mut [0u8; 4]).unwrap(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn read_cursor_seek() -> Result<()> {
+        let flash = MemFlash::blank(8 * KIB, 4 * KIB)?;
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        flash.erase_and_write_block(beginning, &[1, 2, 3, 4])?;
+        let mut cursor = ReadCursor::new(&flash)?;
+        let mut buf = [0u8; 2];
+        assert_eq!(cursor.seek(SeekFrom::End(-4 * KIB as i64)).unwrap(), 4096);
+        assert_eq!(cursor.seek(SeekFrom::Current(1)).unwrap(), 4097);
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert_eq!(cursor.seek(SeekFrom::Current(-3)).unwrap(), 4096);
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(cursor.seek(SeekFrom::End(1)).unwrap(), 8193);
+        assert_eq!(cursor.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            cursor.seek(SeekFrom::Current(-8194)).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,505 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableLocation, ErasableRange, FlashAlign, FlashRead};
+use crate::{Error, Result};
+use crate::{FlashWrite, Location};
+use core::cell::{Cell, RefCell};
+use core::convert::TryFrom;
+use core::mem::MaybeUninit;
+
+/// Size of the error correction record of each block, in Byte.
+const RECORD_SIZE: usize = 4;
+
+/// Record of a block that was erased and not written since.
+const ERASED_RECORD: u32 = 0xffff_ffff;
+
+/// Bit of the record that holds the parity of the entire block.
+const PARITY_BIT: u32 = 1 << 31;
+
+/// Computes the error correction record of the block DATA, followed by
+/// PADDING Byte 0xff.
+/// The record is the XOR of the (1-based) positions of all the set bits,
+/// plus the parity of the number of set bits in PARITY_BIT.
+/// This can correct one flipped bit and detect two flipped bits.
+fn record(data: &[u8], padding: usize) -> u32 {
+    let padding = core::iter::repeat(0xffu8).take(padding);
+    let mut result = 0u32;
+    for (i, x) in data.iter().copied().chain(padding).enumerate() {
+        for bit in 0..8 {
+            if x & (1 << bit) != 0 {
+                result ^= PARITY_BIT | (i * 8 + bit + 1) as u32;
+            }
+        }
+    }
+    result
+}
+
+/// Stores an error correction record for each erasable block of the
+/// underlying flash chip D, and uses it to correct single flipped bits
+/// on reads (and to detect two flipped bits, returning Error::Verify).
+/// The records are in an area at the end of D, and the rest of D is
+/// available as usual.
+/// S is a buffer of one erasable block, used to update the records and
+/// to check entire blocks.
+/// Note: Blocks that were erased (and not written since) are not checked.
+/// Note: Each block of records is rewritten whenever a record in it
+/// changes; erase_and_write_blocks and erase_range update all the records
+/// in a block of records at once.
+pub struct EccFlash<D, S> {
+    flash: D,
+    scratch: RefCell<S>,
+    /// Number of blocks available for data
+    data_blocks: usize,
+    /// Number of bits corrected so far
+    corrected: Cell<usize>,
+    /// Index of the data block that is in scratch (corrected), if any
+    cached: Cell<Option<usize>>,
+}
+
+impl<D: FlashWrite, S: AsRef<[u8]> + AsMut<[u8]>> EccFlash<D, S> {
+    /// FLASH has to report its capacity, which has to be a multiple of its
+    /// erasable block size.
+    /// SCRATCH has to be exactly one erasable block of FLASH.
+    pub fn new(flash: D, scratch: S) -> Result<Self> {
+        let erasable_block_size = flash.erasable_block_size();
+        let capacity = flash.capacity().ok_or(Error::Programmer)?;
+        if scratch.as_ref().len() != erasable_block_size
+            || erasable_block_size < RECORD_SIZE
+        {
+            return Err(Error::Programmer);
+        }
+        if capacity % erasable_block_size != 0 {
+            return Err(Error::Alignment);
+        }
+        // Each block of records covers RECORDS_PER_BLOCK data blocks.
+        let records_per_block = erasable_block_size / RECORD_SIZE;
+        let blocks = capacity / erasable_block_size;
+        let record_blocks =
+            (blocks + records_per_block) / (records_per_block + 1);
+        Ok(Self {
+            flash,
+            scratch: RefCell::new(scratch),
+            data_blocks: blocks - record_blocks,
+            corrected: Cell::new(0),
+            cached: Cell::new(None),
+        })
+    }
+    /// Number of flipped bits that reads have corrected so far.
+    /// Note: Reads of the same block one after the other read and correct
+    /// it only once, so a flipped bit in it counts once; reading the block
+    /// again after other blocks (or after writing) counts it again.
+    pub fn corrected(&self) -> usize {
+        self.corrected.get()
+    }
+    pub fn into_inner(self) -> (D, S) {
+        (self.flash, self.scratch.into_inner())
+    }
+    /// Returns the location of the block of records that contains the
+    /// record of data block INDEX, and the offset of the record in it.
+    fn record_location(
+        &self,
+        index: usize,
+    ) -> Result<(ErasableLocation, usize)> {
+        let records_per_block = self.flash.erasable_block_size() / RECORD_SIZE;
+        let block = self.data_blocks + index / records_per_block;
+        let location = self.flash.block_location(u32::try_from(block)?)?;
+        Ok((location, index % records_per_block * RECORD_SIZE))
+    }
+    /// Returns the index of the data block at LOCATION, if it is one.
+    fn index(&self, location: ErasableLocation) -> Result<usize> {
+        let index = self.flash.location(location)? as usize
+            / self.flash.erasable_block_size();
+        if index < self.data_blocks {
+            Ok(index)
+        } else {
+            Err(Error::OutOfBounds { location: location.into() })
+        }
+    }
+    fn read_record(&self, index: usize) -> Result<u32> {
+        let (location, offset) = self.record_location(index)?;
+        let mut record = [0u8; RECORD_SIZE];
+        self.flash.read_exact(
+            Location::from(location) + offset as Location,
+            &mut record,
+        )?;
+        Ok(u32::from_le_bytes(record))
+    }
+    /// Sets the records of the COUNT data blocks starting at FIRST, the
+    /// I-th of them to RECORD(I), rewriting each block of records once.
+    fn write_records(
+        &self,
+        first: usize,
+        count: usize,
+        record: impl Fn(usize) -> u32,
+    ) -> Result<()> {
+        let records_per_block = self.flash.erasable_block_size() / RECORD_SIZE;
+        let mut scratch = self.scratch.borrow_mut();
+        let scratch = scratch.as_mut();
+        self.cached.set(None);
+        let mut i = 0;
+        while i < count {
+            let (location, offset) = self.record_location(first + i)?;
+            let n = (records_per_block - offset / RECORD_SIZE).min(count - i);
+            self.flash.read_erasable_block(location, scratch)?;
+            let slots = &mut scratch[offset..offset + n * RECORD_SIZE];
+            for (j, slot) in slots.chunks_exact_mut(RECORD_SIZE).enumerate() {
+                slot.copy_from_slice(&record(i + j).to_le_bytes());
+            }
+            self.flash.erase_and_write_block(location, scratch)?;
+            i += n;
+        }
+        Ok(())
+    }
+    fn write_record(&self, index: usize, record: u32) -> Result<()> {
+        self.write_records(index, 1, |_| record)
+    }
+    /// Returns the index of the first of the data blocks that SIZE Byte
+    /// starting at LOCATION touch, and their number, if they are all data
+    /// blocks.
+    fn indices(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<(usize, usize)> {
+        if size == 0 {
+            return Ok((0, 0));
+        }
+        let first = self.index(location)?;
+        let count = ErasableRange::covering(location, size)?.capacity()
+            / self.flash.erasable_block_size();
+        if count > self.data_blocks - first {
+            return Err(Error::OutOfBounds { location: location.into() });
+        }
+        Ok((first, count))
+    }
+    /// Reads the data block INDEX into SCRATCH and corrects it, if
+    /// necessary (unless it is there already).
+    fn read_block(&self, index: usize, scratch: &mut [u8]) -> Result<()> {
+        if self.cached.get() == Some(index) {
+            return Ok(());
+        }
+        self.cached.set(None);
+        self.read_block_uncached(index, scratch)?;
+        self.cached.set(Some(index));
+        Ok(())
+    }
+    fn read_block_uncached(
+        &self,
+        index: usize,
+        scratch: &mut [u8],
+    ) -> Result<()> {
+        let location = self.flash.block_location(u32::try_from(index)?)?;
+        self.flash.read_erasable_block(location, scratch)?;
+        let expected = self.read_record(index)?;
+        if expected == ERASED_RECORD {
+            return Ok(());
+        }
+        let syndrome = record(scratch, 0) ^ expected;
+        if syndrome & PARITY_BIT == 0 {
+            if syndrome == 0 {
+                return Ok(());
+            }
+            // Even number (probably two) of flipped bits
+            return Err(Error::Verify { location: location.into() });
+        }
+        // Odd number (hopefully one) of flipped bits
+        if let Some(position) = (syndrome & !PARITY_BIT).checked_sub(1) {
+            let position = position as usize;
+            let x = scratch
+                .get_mut(position / 8)
+                .ok_or(Error::Verify { location: location.into() })?;
+            *x ^= 1 << (position % 8);
+        } // else the parity bit of the record itself flipped
+        self.corrected.set(self.corrected.get() + 1);
+        Ok(())
+    }
+}
+
+impl<D: FlashWrite, S: AsRef<[u8]> + AsMut<[u8]>> EccFlash<D, S> {
+    /// Reads (and corrects) the SIZE Byte starting at BEGINNING, and
+    /// passes them to COPY in order, each part with its offset.
+    fn read_parts(
+        &self,
+        beginning: Location,
+        size: usize,
+        mut copy: impl FnMut(usize, &[u8]),
+    ) -> Result<()> {
+        let erasable_block_size = self.flash.erasable_block_size();
+        let end = (beginning as usize)
+            .checked_add(size)
+            .filter(|&end| end <= self.data_blocks * erasable_block_size)
+            .ok_or(Error::OutOfBounds { location: beginning })?;
+        let mut scratch = self.scratch.borrow_mut();
+        let scratch = scratch.as_mut();
+        let mut position = beginning as usize;
+        while position < end {
+            let index = position / erasable_block_size;
+            let offset = position % erasable_block_size;
+            let len = (erasable_block_size - offset).min(end - position);
+            self.read_block(index, scratch)?;
+            copy(position - beginning as usize, &scratch[offset..offset + len]);
+            position += len;
+        }
+        Ok(())
+    }
+}
+
+impl<D: FlashWrite, S: AsRef<[u8]> + AsMut<[u8]>> FlashRead for EccFlash<D, S> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        self.read_parts(beginning, buffer.len(), |offset, part| {
+            buffer[offset..offset + part.len()].copy_from_slice(part);
+        })
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        self.read_parts(beginning, buffer.len(), |offset, part| {
+            for (x, &y) in buffer[offset..].iter_mut().zip(part) {
+                x.write(y);
+            }
+        })?;
+        // SAFETY: The parts cover all of BUFFER.
+        Ok(unsafe { crate::assume_init(buffer) })
+    }
+    fn capacity(&self) -> Option<usize> {
+        Some(self.data_blocks * self.flash.erasable_block_size())
+    }
+}
+
+impl<D: FlashAlign, S> FlashAlign for EccFlash<D, S> {
+    fn erasable_block_size(&self) -> usize {
+        self.flash.erasable_block_size()
+    }
+}
+
+impl<D: FlashWrite, S: AsRef<[u8]> + AsMut<[u8]>> FlashWrite
+    for EccFlash<D, S>
+{
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let index = self.index(location)?;
+        self.cached.set(None);
+        self.flash.erase_block(location)?;
+        self.write_record(index, ERASED_RECORD)
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let index = self.index(location)?;
+        let erasable_block_size = self.flash.erasable_block_size();
+        if buffer.len() > erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        self.cached.set(None);
+        self.flash.erase_and_write_block(location, buffer)?;
+        self.write_record(
+            index,
+            record(buffer, erasable_block_size - buffer.len()),
+        )
+    }
+    /// Writes all the data blocks first, and then their records.
+    fn erase_and_write_blocks(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+    ) -> Result<()> {
+        let (first, count) = self.indices(location, buf.len())?;
+        let erasable_block_size = self.flash.erasable_block_size();
+        self.cached.set(None);
+        let mut block = location;
+        for chunk in buf.chunks(erasable_block_size) {
+            self.flash.erase_and_write_block(block, chunk)?;
+            block = block.advance_at_least(chunk.len())?;
+        }
+        self.write_records(first, count, |i| {
+            let beginning = i * erasable_block_size;
+            let end = buf.len().min(beginning + erasable_block_size);
+            let chunk = &buf[beginning..end];
+            record(chunk, erasable_block_size - chunk.len())
+        })
+    }
+    /// Erases all the data blocks in RANGE first, and then marks their
+    /// records as erased.
+    fn erase_range(&self, range: &ErasableRange) -> Result<()> {
+        let (first, count) = self.indices(range.beginning, range.capacity())?;
+        self.cached.set(None);
+        self.flash.erase_range(range)?;
+        self.write_records(first, count, |_| ERASED_RECORD)
+    }
+}
+
+#[cfg(test)]
+mod ecc_tests {
+    use super::*;
+    use crate::mem::MemFlash;
+    use crate::ErasableRange;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn ecc_layout() -> Result<()> {
+        let mut storage = [0xffu8; 64];
+        let mem = MemFlash::new(&mut storage[..], 8)?;
+        // 8 blocks: 5 for data, 3 for records (of 2 data blocks each)
+        let flash = EccFlash::new(mem, [0u8; 8])?;
+        assert_eq!(flash.capacity(), Some(5 * 8));
+        let location = flash.erasable_location(3 * 8).unwrap();
+        flash.erase_and_write_block(location, &[1, 2, 3])?;
+        let first_record_block = flash.erasable_location(5 * 8).unwrap();
+        assert!(matches!(
+            flash.erase_block(first_record_block),
+            Err(Error::OutOfBounds { .. })
+        ));
+        let (mem, _) = flash.into_inner();
+        // The record of data block 3 is the second one in record block 6.
+        let mut buf = [0u8; 8];
+        mem.read_exact(6 * 8, &mut buf)?;
+        assert_ne!(&buf[4..], [0xff; 4]);
+        assert_eq!(&buf[..4], [0xff; 4]);
+        Ok(())
+    }
+
+    /// Counts the erases and writes of blocks of D.
+    struct Counting<D> {
+        flash: D,
+        erases: Cell<usize>,
+        writes: Cell<usize>,
+    }
+    impl<D: FlashRead> FlashRead for Counting<D> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.flash.read_exact(location, buffer)
+        }
+        fn capacity(&self) -> Option<usize> {
+            self.flash.capacity()
+        }
+    }
+    impl<D: FlashAlign> FlashAlign for Counting<D> {
+        fn erasable_block_size(&self) -> usize {
+            self.flash.erasable_block_size()
+        }
+    }
+    impl<D: FlashWrite> FlashWrite for Counting<D> {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.erases.set(self.erases.get() + 1);
+            self.flash.erase_block(location)
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.writes.set(self.writes.get() + 1);
+            self.flash.erase_and_write_block(location, buffer)
+        }
+    }
+
+    #[test]
+    fn ecc_batches_records() -> Result<()> {
+        let mut storage = [0xffu8; 64];
+        let mem = MemFlash::new(&mut storage[..], 8)?;
+        let counting =
+            Counting { flash: mem, erases: Cell::new(0), writes: Cell::new(0) };
+        // 5 data blocks, with records in blocks 5, 6 and 7
+        let flash = EccFlash::new(counting, [0u8; 8])?;
+        let beginning = flash.erasable_location(0).unwrap();
+        flash.erase_and_write_blocks(beginning, &[0x5a; 20])?;
+        let range = ErasableRange::new(beginning, beginning.advance(16)?);
+        flash.erase_range(&range)?;
+        assert!(matches!(
+            flash.erase_and_write_blocks(beginning.advance(32)?, &[0; 9]),
+            Err(Error::OutOfBounds { .. })
+        ));
+        let mut buf = [0u8; 24];
+        flash.read_exact(0, &mut buf)?;
+        assert_eq!(buf[..16], [0xff; 16]);
+        assert_eq!(buf[16..20], [0x5a; 4]);
+        assert_eq!(buf[20..], [0xff; 4]);
+        let (counting, _) = flash.into_inner();
+        // Writing: 3 data blocks, 2 blocks of records; erasing: 2 data
+        // blocks, 1 block of records
+        assert_eq!(counting.writes.get(), 3 + 2 + 1);
+        assert_eq!(counting.erases.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn ecc_corrects_single_bit() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        let mut scratch = [0u8; 4 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let flash = EccFlash::new(mem, &mut scratch[..])?;
+        assert_eq!(flash.capacity(), Some(12 * KIB));
+        let mut data = [0u8; 6 * KIB];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let beginning = flash.erasable_location(0).unwrap();
+        flash.erase_and_write_blocks(beginning, &data)?;
+
+        let (mut mem, scratch) = flash.into_inner();
+        let range = ErasableRange::new(beginning, beginning.advance(8 * KIB)?);
+        for (i, (_, block)) in mem.blocks_mut(&range).enumerate() {
+            block[100 + i] ^= 0x10;
+        }
+        let flash = EccFlash::new(mem, scratch)?;
+        let mut buf = [0u8; 6 * KIB];
+        flash.read_exact(0, &mut buf)?;
+        assert_eq!(buf, data);
+        assert_eq!(flash.corrected(), 2);
+        // Reading a block in small chunks corrects it once.
+        let mut chunk = [0u8; 256];
+        for i in 0..16 {
+            flash.read_exact(i * 256, &mut chunk)?;
+            assert_eq!(chunk[..], data[i as usize * 256..][..256]);
+        }
+        assert_eq!(flash.corrected(), 3);
+
+        // Erased and not written
+        let mut buf = [0u8; 2 * KIB];
+        flash.read_exact(10 * KIB as Location, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0xff));
+        assert_eq!(flash.corrected(), 3);
+
+        let mut uninit = [MaybeUninit::uninit(); 6 * KIB];
+        assert_eq!(flash.read_exact_uninit(0, &mut uninit)?, data);
+        assert_eq!(flash.corrected(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn ecc_detects_double_bit() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        let mut scratch = [0u8; 4 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let flash = EccFlash::new(mem, &mut scratch[..])?;
+        let location = flash.erasable_location(4 * KIB as Location).unwrap();
+        flash.erase_and_write_block(location, &[0x55; 4 * KIB])?;
+
+        let (mut mem, scratch) = flash.into_inner();
+        let range = ErasableRange::new(location, location.advance(4 * KIB)?);
+        for (_, block) in mem.blocks_mut(&range) {
+            block[7] ^= 0x01;
+            block[3000] ^= 0x80;
+        }
+        let flash = EccFlash::new(mem, scratch)?;
+        let mut buf = [0u8; 1];
+        assert!(matches!(
+            flash.read_exact(4 * KIB as Location, &mut buf),
+            Err(Error::Verify { location }) if location == 4 * KIB as Location
+        ));
+        assert_eq!(flash.corrected(), 0);
+
+        flash.erase_block(location)?;
+        flash.read_exact(4 * KIB as Location, &mut buf)?;
+        assert_eq!(buf, [0xff]);
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableRange, Error, FlashWrite, Location, Result};
+
+/// Minimal view of a FlashWrite (with FlashRead and FlashAlign) in terms of
+/// plain locations, for selecting flash backends at run time (for example
+/// via `Box<dyn ErasedFlash>`) without handling ErasableLocation.
+/// Every FlashWrite implements it.
+/// Note: The method names differ from those of FlashRead and FlashWrite so
+/// that calls on a concrete flash are never ambiguous.
+pub trait ErasedFlash {
+    /// Erasable block size in Byte.
+    fn block_size(&self) -> usize;
+    /// Total size of the flash chip in Byte, if known.
+    fn erased_capacity(&self) -> Option<usize>;
+    /// Reads exactly BUFFER.len() Byte starting at BEGINNING.
+    fn read_at(&self, beginning: Location, buffer: &mut [u8]) -> Result<()>;
+    /// Erases and writes BUFFER starting at BEGINNING (see
+    /// FlashWrite::erase_and_write_blocks).
+    /// BEGINNING has to be on a block boundary (otherwise returns
+    /// Error::Alignment).
+    fn write_at(&self, beginning: Location, buffer: &[u8]) -> Result<()>;
+    /// Erases SIZE Byte starting at BEGINNING.
+    /// Both have to be on a block boundary (otherwise returns
+    /// Error::Alignment).
+    fn erase_at(&self, beginning: Location, size: usize) -> Result<()>;
+}
+
+impl<F: FlashWrite> ErasedFlash for F {
+    fn block_size(&self) -> usize {
+        self.erasable_block_size()
+    }
+    fn erased_capacity(&self) -> Option<usize> {
+        self.capacity()
+    }
+    fn read_at(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        self.read_exact(beginning, buffer)
+    }
+    fn write_at(&self, beginning: Location, buffer: &[u8]) -> Result<()> {
+        let beginning =
+            self.erasable_location(beginning).ok_or(Error::Alignment)?;
+        self.erase_and_write_blocks(beginning, buffer)
+    }
+    fn erase_at(&self, beginning: Location, size: usize) -> Result<()> {
+        let beginning =
+            self.erasable_location(beginning).ok_or(Error::Alignment)?;
+        self.erase_range(&ErasableRange::new(
+            beginning,
+            beginning.advance(size)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod erased_tests {
+    use super::*;
+    use crate::mem::MemFlash;
+    const KIB: usize = 1024; // B
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn boxed_mem_flash() -> Result<()> {
+        let flash: Box<dyn ErasedFlash> =
+            Box::new(MemFlash::new(vec![0u8; 16 * KIB], 4 * KIB)?);
+        assert_eq!(flash.block_size(), 4 * KIB);
+        assert_eq!(flash.erased_capacity(), Some(16 * KIB));
+        flash.write_at(4 * KIB as Location, b"hello")?;
+        let mut buf = [0u8; 6];
+        flash.read_at(4 * KIB as Location, &mut buf)?;
+        assert_eq!(&buf, b"hello\xff");
+        flash.erase_at(4 * KIB as Location, 4 * KIB)?;
+        flash.read_at(4 * KIB as Location, &mut buf)?;
+        assert_eq!(buf, [0xff; 6]);
+        flash.read_at(0, &mut buf)?;
+        assert_eq!(buf, [0; 6]);
+        assert!(matches!(flash.write_at(1, b"x"), Err(Error::Alignment)));
+        assert!(matches!(flash.erase_at(0, 1), Err(Error::Alignment)));
+        Ok(())
+    }
+
+    #[test]
+    fn dyn_erased_flash() -> Result<()> {
+        let mut storage = [0u8; 8 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let flash: &dyn ErasedFlash = &mem;
+        flash.write_at(0, &[1, 2, 3])?;
+        let mut buf = [0u8; 4];
+        flash.read_at(0, &mut buf)?;
+        assert_eq!(buf, [1, 2, 3, 0xff]);
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::allocators::FlashAllocate;
+use crate::block;
+use crate::{ErasableRange, FlashWrite, Location};
+use crate::{Error, Result};
+
+/// Final placement of the entries of an ImageBuilder.
+#[derive(Debug)]
+pub struct ImageLayout {
+    /// Name and range of each entry, in the order they were placed
+    pub entries: Vec<(String, ErasableRange)>,
+}
+
+impl ImageLayout {
+    /// Minimal image size (in Byte) that contains all the entries.
+    pub fn total_size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(_, range)| Location::from(range.end) as usize)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Collects the entries of a flash image, each with its own alignment
+/// requirement, and then places them all using an allocator.
+#[derive(Default)]
+pub struct ImageBuilder<'a> {
+    entries: Vec<(String, &'a [u8], Option<block::Size>)>,
+}
+
+impl<'a> ImageBuilder<'a> {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+    /// Adds an entry NAME with contents DATA, aligned to an erasable
+    /// block.
+    pub fn place(&mut self, name: &str, data: &'a [u8]) {
+        self.entries.push((name.into(), data, None));
+    }
+    /// Adds an entry NAME with contents DATA that has to start at a
+    /// multiple of ALIGN.
+    pub fn place_aligned(
+        &mut self,
+        name: &str,
+        data: &'a [u8],
+        align: block::Size,
+    ) {
+        self.entries.push((name.into(), data, Some(align)));
+    }
+    /// Takes a range for each entry from ALLOCATOR, in order (see
+    /// FlashAllocate::take_aligned for aligned entries).
+    /// An alignment has to be a multiple of the erasable block size
+    /// (otherwise returns Error::Alignment).
+    /// If the allocator runs out of space, returns Error::Size.
+    pub fn commit<A: FlashAllocate>(
+        &self,
+        allocator: &mut A,
+    ) -> Result<ImageLayout> {
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for (name, data, align) in &self.entries {
+            let range = match align {
+                None => {
+                    allocator.take_at_least(data.len()).ok_or(Error::Size)?
+                }
+                Some(align) => allocator.take_aligned(data.len(), *align)?,
+            };
+            entries.push((name.clone(), range));
+        }
+        Ok(ImageLayout { entries })
+    }
+    /// Writes the contents of each entry to FLASH, at the range that
+    /// LAYOUT (from commit) has for it.
+    pub fn write<F: FlashWrite>(
+        &self,
+        layout: &ImageLayout,
+        flash: &F,
+    ) -> Result<()> {
+        if layout.entries.len() != self.entries.len() {
+            return Err(Error::Programmer);
+        }
+        for ((_, data, _), (_, range)) in
+            self.entries.iter().zip(&layout.entries)
+        {
+            flash.erase_and_write_blocks(range.beginning, data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod image_tests {
+    use super::*;
+    use crate::allocators::ArenaFlashAllocator;
+    use crate::mem::MemFlash;
+    use crate::{FlashAlign, FlashRead};
+    const KIB: usize = 1024; // B
+
+    fn allocator(flash: &impl FlashAlign) -> ArenaFlashAllocator {
+        let beginning = flash.erasable_location(0).unwrap();
+        let end = beginning.advance(1024 * KIB).unwrap();
+        ArenaFlashAllocator::new(
+            0x2_0000,
+            0x1000,
+            ErasableRange::new(beginning, end),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn image_builder_alignment() -> Result<()> {
+        let flash = MemFlash::blank(1024 * KIB, 4 * KIB)?;
+        let small = [1u8; 100];
+        let large = [2u8; 5 * KIB];
+        let mut builder = ImageBuilder::new();
+        builder.place("a", &small);
+        builder.place_aligned("b", &large, block::Size::B64K);
+        builder.place_aligned("c", &small, block::Size::B4K);
+        builder.place_aligned("d", &small, block::Size::B64K);
+        let layout = builder.commit(&mut allocator(&flash))?;
+        let beginnings: Vec<_> = layout
+            .entries
+            .iter()
+            .map(|(name, range)| {
+                (
+                    name.as_str(),
+                    Location::from(range.beginning),
+                    range.capacity(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            beginnings,
+            [
+                ("a", 0, 4 * KIB),
+                ("b", 0x1_0000, 8 * KIB),
+                // In the space in front of "b"
+                ("c", 0x1000, 4 * KIB),
+                ("d", 0x3_0000, 4 * KIB),
+            ]
+        );
+        assert_eq!(layout.total_size(), 0x3_1000);
+
+        builder.write(&layout, &flash)?;
+        let mut buf = [0u8; 5 * KIB + 1];
+        flash.read_exact(0x1_0000, &mut buf)?;
+        assert_eq!(buf[..5 * KIB], large);
+        assert_eq!(buf[5 * KIB], 0xff);
+        flash.read_exact(0x3_0000, &mut buf[..100])?;
+        assert_eq!(buf[..100], small);
+        Ok(())
+    }
+
+    #[test]
+    fn image_builder_packs_aligned_entries() -> Result<()> {
+        let flash = MemFlash::blank(1024 * KIB, 4 * KIB)?;
+        let data = [3u8; 64 * KIB];
+        let mut builder = ImageBuilder::new();
+        builder.place_aligned("a", &data, block::Size::B64K);
+        builder.place_aligned("b", &data, block::Size::B64K);
+        let layout = builder.commit(&mut allocator(&flash))?;
+        let (_, a) = &layout.entries[0];
+        let (_, b) = &layout.entries[1];
+        assert_eq!(Location::from(a.beginning), 0);
+        assert_eq!(Location::from(b.beginning), Location::from(a.end));
+        assert_eq!(layout.total_size(), 128 * KIB);
+        Ok(())
+    }
+
+    #[test]
+    fn image_builder_errors() -> Result<()> {
+        let flash = MemFlash::blank(8 * KIB, 8 * KIB)?;
+        let mut builder = ImageBuilder::new();
+        builder.place_aligned("a", &[0u8; 4], block::Size::B12K);
+        assert!(matches!(
+            builder.commit(&mut allocator(&flash)),
+            Err(Error::Alignment)
+        ));
+        let huge = vec![0u8; 2048 * KIB];
+        let mut builder = ImageBuilder::new();
+        builder.place("huge", &huge);
+        assert!(matches!(
+            builder.commit(&mut allocator(&flash)),
+            Err(Error::Size)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    ErasableLocation, ErasableRange, FlashAlign, FlashRead, FlashWrite,
+    Location,
+};
+use crate::{Error, Result};
+use core::cell::{Ref, RefCell};
+use core::mem::MaybeUninit;
+
+/// One mutation of a flash chip, as recorded by Journaling.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalEntry {
+    Erase { location: Location, size: usize },
+    Write { location: Location, data: Vec<u8> },
+}
+
+/// Passes everything through to the flash chip D, and records every erase
+/// and write in order, so that they can be replayed later (for example on
+/// another flash chip, in order to reproduce a problem).
+pub struct Journaling<D> {
+    flash: D,
+    entries: RefCell<Vec<JournalEntry>>,
+}
+
+impl<D> Journaling<D> {
+    pub fn new(flash: D) -> Self {
+        Self { flash, entries: RefCell::new(Vec::new()) }
+    }
+    /// All the recorded mutations, in the order they were made.
+    pub fn entries(&self) -> Ref<'_, [JournalEntry]> {
+        Ref::map(self.entries.borrow(), |entries| &entries[..])
+    }
+    /// Applies all the recorded mutations, in order, to TARGET.
+    /// Note: TARGET has to be able to erase and write at the same
+    /// locations (otherwise returns Error::Alignment).
+    pub fn replay<T: FlashWrite>(&self, target: &T) -> Result<()> {
+        for entry in self.entries.borrow().iter() {
+            match entry {
+                JournalEntry::Erase { location, size } => {
+                    let location = target
+                        .erasable_location(*location)
+                        .ok_or(Error::Alignment)?;
+                    target.erase_range(&ErasableRange::new(
+                        location,
+                        location.advance(*size)?,
+                    ))?;
+                }
+                JournalEntry::Write { location, data } => {
+                    let location = target
+                        .erasable_location(*location)
+                        .ok_or(Error::Alignment)?;
+                    target.erase_and_write_block(location, data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn into_inner(self) -> (D, Vec<JournalEntry>) {
+        (self.flash, self.entries.into_inner())
+    }
+}
+
+impl<D: FlashRead> FlashRead for Journaling<D> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        self.flash.read_exact(beginning, buffer)
+    }
+    fn capacity(&self) -> Option<usize> {
+        self.flash.capacity()
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        self.flash.read_exact_uninit(beginning, buffer)
+    }
+}
+
+impl<D: FlashAlign> FlashAlign for Journaling<D> {
+    fn erasable_block_size(&self) -> usize {
+        self.flash.erasable_block_size()
+    }
+    fn preferred_read_chunk(&self) -> usize {
+        self.flash.preferred_read_chunk()
+    }
+}
+
+impl<D: FlashWrite> FlashWrite for Journaling<D> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.flash.erase_block(location)?;
+        self.entries.borrow_mut().push(JournalEntry::Erase {
+            location: location.into(),
+            size: self.erasable_block_size(),
+        });
+        Ok(())
+    }
+    fn coarse_erase_sizes(&self) -> &[usize] {
+        self.flash.coarse_erase_sizes()
+    }
+    fn erase_coarse(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<()> {
+        self.flash.erase_coarse(location, size)?;
+        self.entries
+            .borrow_mut()
+            .push(JournalEntry::Erase { location: location.into(), size });
+        Ok(())
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.flash.erase_and_write_block(location, buffer)?;
+        self.entries.borrow_mut().push(JournalEntry::Write {
+            location: location.into(),
+            data: buffer.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use crate::mem::MemFlash;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn journal_replay() -> Result<()> {
+        let flash =
+            Journaling::new(MemFlash::new(vec![0u8; 32 * KIB], 4 * KIB)?);
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        flash.erase_and_write_blocks(beginning, &[0x42; 6 * KIB])?;
+        flash.erase_range(&ErasableRange::new(
+            beginning.advance(8 * KIB)?,
+            beginning.advance(16 * KIB)?,
+        ))?;
+        flash.erase_and_write_block(beginning, &[1, 2, 3])?;
+        assert_eq!(
+            flash.entries()[..3],
+            [
+                JournalEntry::Write {
+                    location: 4 * KIB as Location,
+                    data: vec![0x42; 4 * KIB]
+                },
+                JournalEntry::Write {
+                    location: 8 * KIB as Location,
+                    data: vec![0x42; 2 * KIB]
+                },
+                JournalEntry::Erase {
+                    location: 12 * KIB as Location,
+                    size: 4 * KIB
+                },
+            ]
+        );
+        assert_eq!(flash.entries().len(), 5);
+
+        let target = MemFlash::new(vec![0u8; 32 * KIB], 4 * KIB)?;
+        flash.replay(&target)?;
+        let (flash, _) = flash.into_inner();
+        assert_eq!(flash.into_inner(), target.into_inner());
+        Ok(())
+    }
+}
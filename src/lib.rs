@@ -4,14 +4,36 @@
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 pub mod allocators;
+pub mod block;
+pub mod chain;
+pub mod checksum;
+pub mod coarsen;
+#[cfg(feature = "std")]
+pub mod cursor;
+pub mod ecc;
+pub mod erased;
+#[cfg(feature = "std")]
+pub mod image;
+#[cfg(feature = "std")]
+pub mod journal;
+pub mod mem;
+#[cfg(feature = "embedded-storage")]
+pub mod nor;
+pub mod rate_limit;
+#[cfg(feature = "std")]
+pub mod std_backend;
+pub mod writer;
 
 #[derive(Debug)]
 #[cfg_attr(feature = "std", derive(thiserror::Error))]
 pub enum Error {
-    #[cfg_attr(feature = "std", error("io"))]
+    #[cfg(not(feature = "std"))]
     Io,
+    #[cfg(feature = "std")]
+    #[error("io: {0}")]
+    Io(#[source] Box<dyn std::error::Error + Send + Sync>),
     #[cfg_attr(
         feature = "std",
         error("alignment is not good enough for erasability of block")
@@ -24,6 +46,59 @@ pub enum Error {
         error("no area of requested size is available")
     )]
     Size,
+    #[cfg_attr(feature = "std", error("arithmetic overflow"))]
+    Overflow,
+    #[cfg_attr(
+        feature = "std",
+        error("access at location {location:#x} is out of bounds")
+    )]
+    OutOfBounds { location: Location },
+    #[cfg_attr(
+        feature = "std",
+        error("verification failed at location {location:#x}")
+    )]
+    Verify { location: Location },
+}
+
+impl Error {
+    /// Returns a stable numeric code identifying the kind of error, for
+    /// reporting in environments without Display:
+    /// Io = 1, Alignment = 2, Programmer = 3, Size = 4, Overflow = 5,
+    /// OutOfBounds = 6, Verify = 7.
+    pub const fn code(&self) -> u32 {
+        match self {
+            Self::Io { .. } => 1,
+            Self::Alignment => 2,
+            Self::Programmer => 3,
+            Self::Size => 4,
+            Self::Overflow => 5,
+            Self::OutOfBounds { .. } => 6,
+            Self::Verify { .. } => 7,
+        }
+    }
+    /// Returns Error::Io caused by SOURCE (which is only kept with std).
+    fn io_caused_by(source: Error) -> Self {
+        #[cfg(feature = "std")]
+        return Self::Io(Box::new(source));
+        #[cfg(not(feature = "std"))]
+        {
+            let _ = source;
+            Self::Io
+        }
+    }
+}
+
+impl From<core::num::TryFromIntError> for Error {
+    fn from(_: core::num::TryFromIntError) -> Self {
+        Self::Overflow
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Self::Io(Box::new(source))
+    }
 }
 
 pub type Result<Q> = core::result::Result<Q, Error>;
@@ -51,25 +126,68 @@ impl ErasableLocation {
         let end = end.location;
         end.saturating_sub(beginning)
     }
+    /// Returns Error::Overflow if that would go past the last Location.
     pub fn advance(&self, amount: usize) -> Result<Self> {
         if amount & (self.erasable_block_mask() as usize) != 0 {
             return Err(Error::Alignment);
         }
         let pos = (self.location as usize)
             .checked_add(amount)
-            .ok_or(Error::Alignment)?;
+            .ok_or(Error::Overflow)?;
         Ok(Self {
-            location: pos.try_into().map_err(|_| Error::Alignment)?,
+            location: pos.try_into()?,
             erasable_block_size: self.erasable_block_size,
         })
     }
+    /// Returns Error::Overflow if that would go past the last Location.
     pub fn advance_at_least(&self, amount: usize) -> Result<Self> {
         // Round up to a multiple of erasable_block_size()
         let diff =
             0usize.wrapping_sub(amount) & (self.erasable_block_mask() as usize);
-        let amount = amount.checked_add(diff).ok_or(Error::Alignment)?;
+        let amount = amount.checked_add(diff).ok_or(Error::Overflow)?;
         self.advance(amount)
     }
+    /// Returns the range of Byte of the erasable block at this location,
+    /// for example for indexing an image in memory.
+    pub fn block_range(&self) -> core::ops::Range<usize> {
+        let beginning = self.location as usize;
+        beginning..beginning + self.erasable_block_size
+    }
+    /// Returns the location of the next erasable block.
+    /// Returns Error::Overflow if there is none.
+    pub fn next_block(&self) -> Result<Self> {
+        let location = Location::try_from(self.erasable_block_size)
+            .ok()
+            .and_then(|size| self.location.checked_add(size))
+            .ok_or(Error::Overflow)?;
+        Ok(Self { location, erasable_block_size: self.erasable_block_size })
+    }
+    /// Returns the location of the previous erasable block.
+    /// Returns Error::Overflow if there is none.
+    pub fn prev_block(&self) -> Result<Self> {
+        self.sub(self.erasable_block_size)
+    }
+    /// Like advance, but goes backwards.
+    /// Returns Error::Overflow if that would go below 0.
+    pub fn sub(&self, amount: usize) -> Result<Self> {
+        if amount & (self.erasable_block_mask() as usize) != 0 {
+            return Err(Error::Alignment);
+        }
+        let location = Location::try_from(amount)
+            .ok()
+            .and_then(|amount| self.location.checked_sub(amount))
+            .ok_or(Error::Overflow)?;
+        Ok(Self { location, erasable_block_size: self.erasable_block_size })
+    }
+    /// Like advance_at_least, but goes backwards.
+    /// Returns Error::Overflow if that would go below 0.
+    pub fn sub_round_up(&self, amount: usize) -> Result<Self> {
+        // Round up to a multiple of erasable_block_size()
+        let diff =
+            0usize.wrapping_sub(amount) & (self.erasable_block_mask() as usize);
+        let amount = amount.checked_add(diff).ok_or(Error::Overflow)?;
+        self.sub(amount)
+    }
 }
 
 impl From<ErasableLocation> for Location {
@@ -84,9 +202,51 @@ pub struct ErasableRange {
     pub end: ErasableLocation,       // note: same erasable_block_size assumed
 }
 impl ErasableRange {
+    /// Note: Panics if BEGINNING > END (see try_new).
     pub fn new(beginning: ErasableLocation, end: ErasableLocation) -> Self {
-        assert!(Location::from(beginning) <= Location::from(end)); // TODO nicer
-        Self { beginning, end }
+        Self::try_new(beginning, end).unwrap()
+    }
+    /// Like new, but returns Error::Programmer if BEGINNING > END.
+    pub fn try_new(
+        beginning: ErasableLocation,
+        end: ErasableLocation,
+    ) -> Result<Self> {
+        if Location::from(beginning) <= Location::from(end) {
+            Ok(Self { beginning, end })
+        } else {
+            Err(Error::Programmer)
+        }
+    }
+    /// Returns the smallest Range starting at BEGINNING that contains at
+    /// least SIZE Byte.
+    pub fn covering(beginning: ErasableLocation, size: usize) -> Result<Self> {
+        Ok(Self { beginning, end: beginning.advance_at_least(size)? })
+    }
+    /// Returns the Range [BASE, BASE + TILE_SIZE * COUNT), for COUNT
+    /// consecutive tiles of TILE_SIZE Byte each.
+    /// TILE_SIZE has to be a multiple of the erasable block size.
+    pub fn tile(
+        base: ErasableLocation,
+        tile_size: usize,
+        count: usize,
+    ) -> Result<Self> {
+        if tile_size & (base.erasable_block_mask() as usize) != 0 {
+            return Err(Error::Alignment);
+        }
+        let size = tile_size.checked_mul(count).ok_or(Error::Overflow)?;
+        (base.location as usize)
+            .checked_add(size)
+            .and_then(|end| Location::try_from(end).ok())
+            .ok_or(Error::Overflow)?;
+        Ok(Self { beginning: base, end: base.advance(size)? })
+    }
+    /// Like new, but swaps A and B if they are in the wrong order.
+    pub fn from_unordered(a: ErasableLocation, b: ErasableLocation) -> Self {
+        if Location::from(a) <= Location::from(b) {
+            Self { beginning: a, end: b }
+        } else {
+            Self { beginning: b, end: a }
+        }
     }
     /// Splits the Range after at least SIZE Byte, if possible.
     /// Return the first part. Retain the second part.
@@ -100,16 +260,605 @@ impl ErasableRange {
             None
         }
     }
+    /// Like take_at_least, but SIZE has to be a multiple of the erasable
+    /// block size already (otherwise returns Error::Alignment), and it
+    /// has to fit (otherwise returns Error::Size).
+    pub fn take_exact(&mut self, size: usize) -> Result<Self> {
+        let x_beginning = self.beginning;
+        let x_end = self.beginning.advance(size)?;
+        if Location::from(x_end) <= Location::from(self.end) {
+            *self = Self::new(x_end, self.end);
+            Ok(Self::new(x_beginning, x_end))
+        } else {
+            Err(Error::Size)
+        }
+    }
+    /// Returns whether LOCATION is in the Range.
+    pub const fn contains(&self, location: Location) -> bool {
+        self.beginning.location <= location && location < self.end.location
+    }
+    /// Returns whether OTHER is entirely in the Range.
+    pub const fn contains_range(&self, other: &Self) -> bool {
+        self.beginning.location <= other.beginning.location
+            && other.end.location <= self.end.location
+    }
+    /// Returns whether the Range touches more ALIGN-sized (and aligned)
+    /// regions than a Range of its size needs to, for example whether a
+    /// Range of at most 64 KiB crosses a multiple of 64 KiB.
+    /// (Then erasing it in units of ALIGN takes more erases than needed.)
+    pub const fn crosses_boundary(&self, align: block::Size) -> bool {
+        let align = align as usize;
+        let beginning = self.beginning.location as usize;
+        let end = self.end.location as usize;
+        if beginning == end {
+            return false;
+        }
+        let regions = (end - 1) / align - beginning / align + 1;
+        // Rounding up without adding, which could overflow near the top of
+        // the address space.
+        let size = end - beginning;
+        regions > size / align + (size % align != 0) as usize
+    }
+    /// Returns the part of the Range that is also in OTHER, if any.
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        let beginning = self.beginning.location.max(other.beginning.location);
+        let end = self.end.location.min(other.end.location);
+        let erasable_block_size = self.beginning.erasable_block_size;
+        (beginning < end).then_some(Self {
+            beginning: ErasableLocation {
+                location: beginning,
+                erasable_block_size,
+            },
+            end: ErasableLocation { location: end, erasable_block_size },
+        })
+    }
+    /// Returns the union of the Range and OTHER if they overlap or are
+    /// adjacent, otherwise None.
+    pub fn merge(&self, other: &Self) -> Option<Self> {
+        if self.beginning.location > other.end.location
+            || other.beginning.location > self.end.location
+        {
+            return None;
+        }
+        let erasable_block_size = self.beginning.erasable_block_size;
+        let beginning = self.beginning.location.min(other.beginning.location);
+        let end = self.end.location.max(other.end.location);
+        Some(Self {
+            beginning: ErasableLocation {
+                location: beginning,
+                erasable_block_size,
+            },
+            end: ErasableLocation { location: end, erasable_block_size },
+        })
+    }
+    /// Iterates over the locations of the erasable blocks in the Range.
+    pub fn blocks(&self) -> impl Iterator<Item = ErasableLocation> {
+        let erasable_block_size = self.beginning.erasable_block_size;
+        (self.beginning.location..self.end.location)
+            .step_by(erasable_block_size)
+            .map(move |location| ErasableLocation {
+                location,
+                erasable_block_size,
+            })
+    }
     /// in Byte
     pub fn capacity(&self) -> usize {
         ErasableLocation::extent(self.beginning, self.end) as usize
     }
+    /// Returns the erasable location nearest the center of the Range,
+    /// rounded down to an erasable block boundary.
+    pub fn midpoint(&self) -> ErasableLocation {
+        let half = (self.capacity() / 2)
+            & !(self.beginning.erasable_block_mask() as usize);
+        // Note: beginning + half <= end, so this fits into a Location.
+        ErasableLocation {
+            location: self.beginning.location + half as Location,
+            erasable_block_size: self.beginning.erasable_block_size,
+        }
+    }
+    /// Splits the Range at its midpoint (see midpoint).
+    /// Returns the lower part and the upper part.
+    pub fn split_at_midpoint(&self) -> (Self, Self) {
+        let midpoint = self.midpoint();
+        (Self::new(self.beginning, midpoint), Self::new(midpoint, self.end))
+    }
+    /// Returns the part of the Range starting at the first multiple of
+    /// ALIGN, that is the Range with only its beginning moved up.
+    /// Returns Error::Size if there is no multiple of ALIGN in the Range.
+    /// ALIGN has to be a multiple of the erasable block size (otherwise
+    /// returns Error::Alignment).
+    pub fn align_start_up(&self, align: block::Size) -> Result<Self> {
+        let align = usize::from(align);
+        if align % self.beginning.erasable_block_size != 0 {
+            return Err(Error::Alignment);
+        }
+        let beginning = self.beginning.location as usize;
+        let beginning = beginning
+            .checked_add((align - beginning % align) % align)
+            .filter(|&beginning| beginning <= self.end.location as usize)
+            .ok_or(Error::Size)?;
+        Ok(Self {
+            beginning: ErasableLocation {
+                location: beginning as Location,
+                erasable_block_size: self.beginning.erasable_block_size,
+            },
+            end: self.end,
+        })
+    }
+    /// Splits the Range at every multiple of ALIGN inside it, so that each
+    /// part (except maybe the first and the last) is exactly one aligned
+    /// unit of ALIGN.
+    /// ALIGN has to be a multiple of the erasable block size (otherwise
+    /// returns Error::Alignment).
+    #[cfg(feature = "std")]
+    pub fn split_at_alignment(&self, align: block::Size) -> Result<Vec<Self>> {
+        let align = usize::from(align);
+        let erasable_block_size = self.beginning.erasable_block_size;
+        if align % erasable_block_size != 0 {
+            return Err(Error::Alignment);
+        }
+        let end = self.end.location as usize;
+        let mut result = Vec::new();
+        let mut beginning = self.beginning.location as usize;
+        while beginning < end {
+            let boundary = (beginning / align + 1).saturating_mul(align);
+            let part_end = boundary.min(end);
+            result.push(Self {
+                beginning: ErasableLocation {
+                    location: beginning as Location,
+                    erasable_block_size,
+                },
+                end: ErasableLocation {
+                    location: part_end as Location,
+                    erasable_block_size,
+                },
+            });
+            beginning = part_end;
+        }
+        Ok(result)
+    }
+}
+
+/// Returns the minimal image size (in Byte) that contains all the RANGES,
+/// or 0 if there are none.
+pub fn required_image_size(ranges: &[ErasableRange]) -> usize {
+    ranges
+        .iter()
+        .map(|range| Location::from(range.end) as usize)
+        .max()
+        .unwrap_or(0)
+}
+
+fn is_within(range: &ErasableRange, bounds: &ErasableRange) -> bool {
+    Location::from(range.beginning) >= Location::from(bounds.beginning)
+        && Location::from(range.end) <= Location::from(bounds.end)
+}
+
+fn overlap(a: &ErasableRange, b: &ErasableRange) -> bool {
+    Location::from(a.beginning) < Location::from(b.end)
+        && Location::from(b.beginning) < Location::from(a.end)
+}
+
+/// Makes sure that all the RANGES are within BOUNDS (otherwise returns
+/// Error::OutOfBounds with the beginning of the first range that isn't),
+/// and that no two of them overlap (otherwise returns Error::Programmer).
+pub fn validate_within(
+    ranges: &[ErasableRange],
+    bounds: &ErasableRange,
+) -> Result<()> {
+    if let Some(range) = ranges.iter().find(|range| !is_within(range, bounds)) {
+        return Err(Error::OutOfBounds { location: range.beginning.into() });
+    }
+    for (i, a) in ranges.iter().enumerate() {
+        if ranges[i + 1..].iter().any(|b| overlap(a, b)) {
+            return Err(Error::Programmer);
+        }
+    }
+    Ok(())
+}
+
+/// One problem with a layout, as found by lint_layout.
+/// The fields are indices into the ranges that were checked.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutProblem {
+    OutOfBounds { index: usize },
+    Overlap { a: usize, b: usize },
+}
+
+/// Like validate_within, but returns all the problems (in order of the
+/// RANGES) instead of just the first.
+#[cfg(feature = "std")]
+pub fn lint_layout(
+    ranges: &[ErasableRange],
+    bounds: &ErasableRange,
+) -> Vec<LayoutProblem> {
+    let mut result = Vec::new();
+    for (i, a) in ranges.iter().enumerate() {
+        if !is_within(a, bounds) {
+            result.push(LayoutProblem::OutOfBounds { index: i });
+        }
+        for (j, b) in ranges.iter().enumerate().skip(i + 1) {
+            if overlap(a, b) {
+                result.push(LayoutProblem::Overlap { a: i, b: j });
+            }
+        }
+    }
+    result
+}
+
+/// Returns BUFFER as initialized.
+/// # Safety
+/// Every element of BUFFER has to be initialized.
+pub(crate) unsafe fn assume_init(
+    buffer: &mut [core::mem::MaybeUninit<u8>],
+) -> &mut [u8] {
+    // MaybeUninit<u8> has the same layout as u8.
+    &mut *(buffer as *mut [core::mem::MaybeUninit<u8>] as *mut [u8])
+}
+
+/// Size of the stack buffer used for streaming reads.
+const STREAM_CHUNK_SIZE: usize = 256;
+
+/// Reads SIZE Byte starting at BEGINNING, a chunk at a time, and calls F
+/// with the location and the contents of each chunk.
+/// F returns whether to continue.
+fn stream<R: FlashRead + ?Sized>(
+    flash: &R,
+    beginning: Location,
+    size: usize,
+    mut f: impl FnMut(Location, &[u8]) -> Result<bool>,
+) -> Result<()> {
+    let size: Location = size.try_into()?;
+    let end = beginning.checked_add(size).ok_or(Error::Overflow)?;
+    let mut buffer = [0u8; STREAM_CHUNK_SIZE];
+    let mut location = beginning;
+    while location < end {
+        let len = ((end - location) as usize).min(buffer.len());
+        let chunk = &mut buffer[..len];
+        flash.read_exact(location, chunk)?;
+        if !f(location, chunk)? {
+            break;
+        }
+        location += len as Location;
+    }
+    Ok(())
+}
+
+/// Returns whether all SIZE Byte starting at BEGINNING are erased (0xff).
+fn is_erased<R: FlashRead + ?Sized>(
+    flash: &R,
+    beginning: Location,
+    size: usize,
+) -> Result<bool> {
+    let mut erased = true;
+    stream(flash, beginning, size, |_, chunk| {
+        erased = chunk.iter().all(|&x| x == 0xff);
+        Ok(erased)
+    })?;
+    Ok(erased)
 }
 
 pub trait FlashRead {
     /// Read exactly the right amount from the location BEGINNING to fill the
     /// entire BUFFER that was passed.
     fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()>;
+    /// Total size of the flash chip in Byte, if known.
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
+    /// Like read_exact, but reads into a BUFFER that doesn't have to be
+    /// initialized, and returns it as initialized.
+    /// Note: By default, this zeroes BUFFER first; implementations that
+    /// can fill it directly should override it, and then have to write
+    /// all of BUFFER.
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [core::mem::MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        for x in buffer.iter_mut() {
+            x.write(0);
+        }
+        // SAFETY: All of BUFFER was just initialized.
+        let buffer = unsafe { assume_init(buffer) };
+        self.read_exact(beginning, buffer)?;
+        Ok(buffer)
+    }
+    /// Like read_exact, but if capacity() is known, first makes sure that
+    /// the entire read is within the flash chip.
+    /// Otherwise returns Error::OutOfBounds with the location BEGINNING.
+    fn read_exact_checked(
+        &self,
+        beginning: Location,
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        let end = (beginning as usize)
+            .checked_add(buffer.len())
+            .ok_or(Error::OutOfBounds { location: beginning })?;
+        if let Some(capacity) = self.capacity() {
+            if end > capacity {
+                return Err(Error::OutOfBounds { location: beginning });
+            }
+        }
+        self.read_exact(beginning, buffer)
+    }
+    /// Compares the flash contents starting at BEGINNING with REFERENCE.
+    /// Returns Error::Verify with the location of the first mismatch, if
+    /// any.
+    fn verify_against(
+        &self,
+        beginning: Location,
+        reference: &[u8],
+    ) -> Result<()> {
+        match self.first_diff_offset(beginning, reference)? {
+            Some(location) => {
+                Err(Error::Verify { location: location as Location })
+            }
+            None => Ok(()),
+        }
+    }
+    /// Compares the flash contents starting at BEGINNING with REFERENCE,
+    /// and returns the location of the first Byte that differs, if any.
+    fn first_diff_offset(
+        &self,
+        beginning: Location,
+        reference: &[u8],
+    ) -> Result<Option<usize>> {
+        let mut result = None;
+        stream(self, beginning, reference.len(), |location, chunk| {
+            let offset = (location - beginning) as usize;
+            let expected = &reference[offset..offset + chunk.len()];
+            result = chunk
+                .iter()
+                .zip(expected)
+                .position(|(a, b)| a != b)
+                .map(|i| location as usize + i);
+            Ok(result.is_none())
+        })?;
+        Ok(result)
+    }
+    /// Returns whether BUFFER could be programmed at BEGINNING without
+    /// erasing first, that is whether programming would only have to
+    /// clear bits.
+    fn write_is_clean(
+        &self,
+        beginning: Location,
+        buffer: &[u8],
+    ) -> Result<bool> {
+        let mut clean = true;
+        stream(self, beginning, buffer.len(), |location, chunk| {
+            let offset = (location - beginning) as usize;
+            let new = &buffer[offset..offset + chunk.len()];
+            clean = chunk.iter().zip(new).all(|(&old, &new)| new & old == new);
+            Ok(clean)
+        })?;
+        Ok(clean)
+    }
+    /// Returns how many erasable blocks writing DATA at START would have to
+    /// erase, given the current contents (see write_is_clean).
+    fn count_erases_for(
+        &self,
+        start: ErasableLocation,
+        data: &[u8],
+    ) -> Result<usize> {
+        let mut location = start;
+        let mut count = 0;
+        for chunk in data.chunks(start.erasable_block_size()) {
+            if !self.write_is_clean(location.into(), chunk)? {
+                count += 1;
+            }
+            location = location.advance_at_least(chunk.len())?;
+        }
+        Ok(count)
+    }
+    /// Compares the flash contents starting at START with REFERENCE, an
+    /// erasable block at a time, and returns the differing blocks, with
+    /// adjacent ones merged into one range.
+    #[cfg(feature = "std")]
+    fn diff_ranges(
+        &self,
+        start: ErasableLocation,
+        reference: &[u8],
+    ) -> Result<Vec<ErasableRange>> {
+        let mut result: Vec<ErasableRange> = Vec::new();
+        let mut location = start;
+        for chunk in reference.chunks(start.erasable_block_size()) {
+            let next = location.advance_at_least(chunk.len())?;
+            match self.verify_against(location.into(), chunk) {
+                Ok(()) => {}
+                Err(Error::Verify { .. }) => match result.last_mut() {
+                    Some(last)
+                        if Location::from(last.end)
+                            == Location::from(location) =>
+                    {
+                        last.end = next;
+                    }
+                    _ => result.push(ErasableRange::new(location, next)),
+                },
+                Err(e) => return Err(e),
+            }
+            location = next;
+        }
+        Ok(result)
+    }
+    /// Checks that the remainder of the last erasable block after DATA_SIZE
+    /// Byte starting at BEGINNING is all PAD (see erase_and_write_padded).
+    /// Otherwise returns Error::Verify with the first location that isn't.
+    fn verify_padding(
+        &self,
+        beginning: ErasableLocation,
+        data_size: usize,
+        pad: u8,
+    ) -> Result<()> {
+        let end = Location::from(beginning.advance_at_least(data_size)?);
+        let tail = Location::from(beginning) + data_size as Location;
+        let mut result = Ok(());
+        stream(self, tail, (end - tail) as usize, |location, chunk| {
+            if let Some(i) = chunk.iter().position(|&x| x != pad) {
+                result =
+                    Err(Error::Verify { location: location + i as Location });
+            }
+            Ok(result.is_ok())
+        })?;
+        result
+    }
+    /// Returns the first erasable block in RANGE that is entirely erased
+    /// (0xff), or None if there is none.
+    fn first_erased_block(
+        &self,
+        range: &ErasableRange,
+    ) -> Result<Option<ErasableLocation>> {
+        let erasable_block_size = range.beginning.erasable_block_size();
+        for location in range.blocks() {
+            if is_erased(self, location.into(), erasable_block_size)? {
+                return Ok(Some(location));
+            }
+        }
+        Ok(None)
+    }
+    /// Returns a 64 bit hash (FNV-1a) of the contents of RANGE.
+    /// Note: This is meant for finding identical regions (for
+    /// deduplication), not for integrity checks.
+    fn region_hash(&self, range: &ErasableRange) -> Result<u64> {
+        let mut hash = checksum::Fnv1a64::new();
+        stream(self, range.beginning.into(), range.capacity(), |_, chunk| {
+            hash.update(chunk);
+            Ok(true)
+        })?;
+        Ok(hash.finalize())
+    }
+    /// Reads the last BLOCKS erasable blocks of RANGE into BUF.
+    /// BUF has to be exactly BLOCKS erasable blocks long, and RANGE has
+    /// to have at least BLOCKS erasable blocks (otherwise returns
+    /// Error::Size).
+    fn read_tail(
+        &self,
+        range: &ErasableRange,
+        blocks: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let size = blocks
+            .checked_mul(range.end.erasable_block_size())
+            .filter(|&size| size == buf.len() && size <= range.capacity())
+            .ok_or(Error::Size)?;
+        self.read_exact(range.end.sub(size)?.into(), buf)
+    }
+    /// Returns the CRC-32 (IEEE) of the contents of RANGE.
+    fn crc32(&self, range: &ErasableRange) -> Result<u32> {
+        let mut crc = checksum::Crc32::new();
+        stream(self, range.beginning.into(), range.capacity(), |_, chunk| {
+            crc.update(chunk);
+            Ok(true)
+        })?;
+        Ok(crc.finalize())
+    }
+    /// Returns the SHA-256 digest of the contents of RANGE.
+    /// Note: Any failure to read RANGE is returned as Error::Io.
+    #[cfg(feature = "std")]
+    fn sha256(&self, range: &ErasableRange) -> Result<[u8; 32]> {
+        use sha2::Digest;
+        let mut hasher = sha2::Sha256::new();
+        stream(self, range.beginning.into(), range.capacity(), |_, chunk| {
+            hasher.update(chunk);
+            Ok(true)
+        })
+        .map_err(|e| match e {
+            e @ Error::Io { .. } => e,
+            e => Error::io_caused_by(e),
+        })?;
+        Ok(hasher.finalize().into())
+    }
+    /// Returns whether the erasable block at LOCATION is erased (all
+    /// 0xff).
+    fn is_blank(&self, location: ErasableLocation) -> Result<bool> {
+        is_erased(self, location.into(), location.erasable_block_size())
+    }
+    /// Returns whether all of RANGE is erased (0xff).
+    fn is_range_blank(&self, range: &ErasableRange) -> Result<bool> {
+        is_erased(self, range.beginning.into(), range.capacity())
+    }
+    /// Returns the byte that every byte of the erasable block at LOCATION
+    /// is, or None if they differ.
+    fn block_uniform_byte(
+        &self,
+        location: ErasableLocation,
+    ) -> Result<Option<u8>> {
+        let mut result = None;
+        stream(
+            self,
+            location.into(),
+            location.erasable_block_size(),
+            |_, chunk| {
+                let x = *result.get_or_insert(chunk[0]);
+                if chunk.iter().all(|&y| y == x) {
+                    Ok(true)
+                } else {
+                    result = None;
+                    Ok(false)
+                }
+            },
+        )?;
+        Ok(result)
+    }
+    /// Reads a length field of LEN_BYTES Byte (at most 8) at AT, and then
+    /// returns that many Byte following it.
+    /// Returns Error::Size if the length is more than MAX_LEN (for example
+    /// because the data is corrupt).
+    #[cfg(feature = "std")]
+    fn read_length_prefixed(
+        &self,
+        at: Location,
+        len_bytes: usize,
+        little_endian: bool,
+        max_len: usize,
+    ) -> Result<Vec<u8>> {
+        if len_bytes == 0 || len_bytes > 8 {
+            return Err(Error::Programmer);
+        }
+        let mut field = [0u8; 8];
+        let field = &mut field[..len_bytes];
+        self.read_exact(at, field)?;
+        if little_endian {
+            field.reverse();
+        }
+        let len = field.iter().fold(0u64, |len, &x| (len << 8) | u64::from(x));
+        let len = usize::try_from(len)?;
+        if len > max_len {
+            return Err(Error::Size);
+        }
+        let beginning =
+            at.checked_add(len_bytes as Location).ok_or(Error::Overflow)?;
+        let mut result = vec![0u8; len];
+        self.read_exact_checked(beginning, &mut result)?;
+        Ok(result)
+    }
+    /// Reads SIZE Byte starting at BEGINNING and writes them to WRITER,
+    /// preferred_read_chunk() Byte (but at least one Byte) at a time.
+    #[cfg(feature = "std")]
+    fn dump_to_writer(
+        &self,
+        beginning: Location,
+        size: usize,
+        writer: &mut dyn std::io::Write,
+    ) -> Result<()>
+    where
+        Self: FlashAlign + Sized,
+    {
+        let size: Location = size.try_into()?;
+        let end = beginning.checked_add(size).ok_or(Error::Overflow)?;
+        let chunk_size = self.preferred_read_chunk().max(1);
+        let mut buffer = vec![0u8; chunk_size.min(size as usize)];
+        let mut location = beginning;
+        while location < end {
+            let len = ((end - location) as usize).min(buffer.len());
+            let chunk = &mut buffer[..len];
+            self.read_exact(location, chunk)?;
+            writer.write_all(chunk)?;
+            location += len as Location;
+        }
+        Ok(())
+    }
 }
 
 pub trait FlashAlign {
@@ -119,6 +868,12 @@ pub trait FlashAlign {
     fn erasable_block_mask(&self) -> u32 {
         (self.erasable_block_size() as u32) - 1
     }
+    /// Size (in Byte) of the chunks that sequential reads should use.
+    /// Note: Can be smaller than erasable_block_size().
+    /// Note: Has to be non-zero (users treat 0 as 1).
+    fn preferred_read_chunk(&self) -> usize {
+        self.erasable_block_size()
+    }
     fn is_aligned(&self, location: Location) -> bool {
         (location & self.erasable_block_mask()) == 0
     }
@@ -132,6 +887,16 @@ pub trait FlashAlign {
         self.is_aligned(location)
             .then_some(ErasableLocation { location, erasable_block_size })
     }
+    /// Returns the erasable location of the erasable block with the given
+    /// INDEX, that is INDEX * erasable_block_size().
+    /// Returns Error::Overflow if that is not a valid location.
+    fn block_location(&self, index: u32) -> Result<ErasableLocation> {
+        let erasable_block_size = self.erasable_block_size();
+        let location = Location::try_from(erasable_block_size)?
+            .checked_mul(index)
+            .ok_or(Error::Overflow)?;
+        Ok(ErasableLocation { location, erasable_block_size })
+    }
     /// Given an erasable location, returns the corresponding location
     /// IF the erasable location is compatible with our instance.
     fn location(
@@ -144,6 +909,18 @@ pub trait FlashAlign {
             Err(Error::Alignment)
         }
     }
+    /// Determine how many Byte a layout of entries of the given SIZES
+    /// would occupy, with each entry rounded up to a multiple of
+    /// erasable_block_size() separately.
+    /// This does not allocate anything.
+    fn footprint(&self, sizes: &[usize]) -> Result<usize> {
+        let mask = self.erasable_block_mask() as usize;
+        sizes.iter().try_fold(0usize, |total, &size| {
+            let diff = 0usize.wrapping_sub(size) & mask;
+            let size = size.checked_add(diff).ok_or(Error::Overflow)?;
+            total.checked_add(size).ok_or(Error::Overflow)
+        })
+    }
 }
 
 pub trait FlashWrite: FlashRead + FlashAlign {
@@ -160,6 +937,73 @@ pub trait FlashWrite: FlashRead + FlashAlign {
         Ok(())
     }
     fn erase_block(&self, location: ErasableLocation) -> Result<()>;
+    /// Whether erase_range should skip erase units that already read back
+    /// as erased (0xff), in order to reduce wear.
+    /// Note: Only worth it if reading is much cheaper than erasing.
+    fn lazy_erase(&self) -> bool {
+        false
+    }
+    /// Erases the block at LOCATION unless it already reads back as
+    /// erased (0xff).
+    /// Returns whether it erased.
+    fn erase_if_needed(&self, location: ErasableLocation) -> Result<bool> {
+        if is_erased(
+            self,
+            self.location(location)?,
+            self.erasable_block_size(),
+        )? {
+            return Ok(false);
+        }
+        self.erase_block(location)?;
+        Ok(true)
+    }
+    /// Sizes (in Byte) of erase units coarser than erasable_block_size()
+    /// that the device can erase in one operation, in descending order.
+    /// Note: Each is assumed to be a power of two.
+    fn coarse_erase_sizes(&self) -> &[usize] {
+        &[]
+    }
+    /// Erases SIZE Byte starting at LOCATION in one operation.
+    /// Note: SIZE is one of coarse_erase_sizes(), and LOCATION is aligned
+    /// to SIZE.
+    fn erase_coarse(
+        &self,
+        _location: ErasableLocation,
+        _size: usize,
+    ) -> Result<()> {
+        Err(Error::Programmer)
+    }
+    /// Erases the entire RANGE, using the fewest erase operations
+    /// (coarsest units first), falling back to erase_block for the rest.
+    /// If lazy_erase(), skips units that are already erased.
+    fn erase_range(&self, range: &ErasableRange) -> Result<()> {
+        let mut location = range.beginning;
+        let end = self.location(range.end)?;
+        loop {
+            let beginning = self.location(location)?;
+            if beginning >= end {
+                break;
+            }
+            let remainder = (end - beginning) as usize;
+            let size = self
+                .coarse_erase_sizes()
+                .iter()
+                .copied()
+                .find(|&size| {
+                    size <= remainder && beginning as usize % size == 0
+                })
+                .unwrap_or_else(|| self.erasable_block_size());
+            if self.lazy_erase() && is_erased(self, beginning, size)? {
+                // Already erased
+            } else if size == self.erasable_block_size() {
+                self.erase_block(location)?;
+            } else {
+                self.erase_coarse(location, size)?;
+            }
+            location = location.advance(size)?;
+        }
+        Ok(())
+    }
     /// Note: If BUFFER.len() < erasable_block_size(), it has to erase the
     /// remainder anyway.
     fn erase_and_write_block(
@@ -167,6 +1011,46 @@ pub trait FlashWrite: FlashRead + FlashAlign {
         location: ErasableLocation,
         buffer: &[u8],
     ) -> Result<()>;
+    /// Writes BUFFER to the erasable block at LOCATION, which is already
+    /// erased, so devices that can write without erasing should not erase
+    /// it again.
+    /// By default, erases anyway (see erase_and_write_block).
+    fn write_erased_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.erase_and_write_block(location, buffer)
+    }
+    /// Makes the erasable block at LOCATION contain BUFFER (erased after
+    /// BUFFER.len()), like erase_and_write_block, but does nothing if it
+    /// already does, and does not erase it if it is already erased.
+    fn write_block_opt(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size() {
+            return Err(Error::Programmer);
+        }
+        let beginning = self.location(location)?;
+        let (mut same, mut erased) = (true, true);
+        stream(self, beginning, self.erasable_block_size(), |l, chunk| {
+            let offset = (l - beginning) as usize;
+            for (i, &x) in chunk.iter().enumerate() {
+                erased &= x == 0xff;
+                same &= x == *buffer.get(offset + i).unwrap_or(&0xff);
+            }
+            Ok(same || erased)
+        })?;
+        if same {
+            Ok(())
+        } else if erased {
+            self.write_erased_block(location, buffer)
+        } else {
+            self.erase_and_write_block(location, buffer)
+        }
+    }
 
     // FIXME: sanity check callers
     fn erase_and_write_blocks(
@@ -176,68 +1060,544 @@ pub trait FlashWrite: FlashRead + FlashAlign {
     ) -> Result<()> {
         let mut location = location;
         let erasable_block_size = self.erasable_block_size();
-        for chunk in buf.chunks(erasable_block_size) {
+        let mut chunks = buf.chunks(erasable_block_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none();
+            // Only the last chunk can be partial.
+            if !last && chunk.len() != erasable_block_size {
+                return Err(Error::Programmer);
+            }
             self.erase_and_write_block(location, chunk)?;
-            if chunk.len() != erasable_block_size {
-                // TODO: Only allow on last chunk
-                break;
+            if !last {
+                location = location.advance(erasable_block_size)?;
             }
-            location = location.advance(erasable_block_size)?;
         }
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use core::cell::RefCell;
-    const KIB: usize = 1024; // B
-    const ERASABLE_BLOCK_SIZE: usize = 128 * KIB;
-
-    struct FlashImage<'a> {
-        buf: RefCell<&'a mut [u8]>,
-        erasable_block_size: usize,
-    }
-
-    impl<'a> FlashImage<'a> {
-        pub fn new(buf: &'a mut [u8]) -> Self {
-            Self {
-                buf: RefCell::new(buf),
-                erasable_block_size: ERASABLE_BLOCK_SIZE,
+    /// Like erase_and_write_blocks, but first checks that all the blocks
+    /// it would write are valid, so that it doesn't write any block if
+    /// they are not (otherwise returns Error::Overflow, or
+    /// Error::OutOfBounds if the flash chip knows its capacity).
+    /// Note: Errors of the flash chip itself can still happen after some
+    /// blocks were written.
+    fn write_checked(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+    ) -> Result<()> {
+        let beginning = self.location(location)?;
+        let end = location.advance_at_least(buf.len())?;
+        if let Some(capacity) = self.capacity() {
+            if Location::from(end) as usize > capacity {
+                return Err(Error::OutOfBounds { location: beginning });
             }
         }
+        self.erase_and_write_blocks(location, buf)
     }
-
-    impl FlashRead for FlashImage<'_> {
-        fn read_exact(
-            &self,
-            location: Location,
-            buffer: &mut [u8],
-        ) -> Result<()> {
-            let len = buffer.len();
-            let buf = self.buf.borrow();
-            let block = &buf[location as usize..];
-            let block = &block[0..len];
-            buffer[..].copy_from_slice(block);
-            Ok(())
+    /// Like erase_and_write_blocks, but then reads the data back and
+    /// compares it to BUF.
+    /// Returns Error::Io if they differ.
+    fn write_verified(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+    ) -> Result<()> {
+        self.erase_and_write_blocks(location, buf)?;
+        self.verify_against(self.location(location)?, buf).map_err(
+            |e| match e {
+                e @ Error::Verify { .. } => Error::io_caused_by(e),
+                e => e,
+            },
+        )
+    }
+    /// Erases the block at LOCATION and then makes sure that it actually
+    /// reads back as erased (0xff).
+    /// Otherwise returns Error::Verify with the first location that isn't.
+    fn erase_verified(&self, location: ErasableLocation) -> Result<()> {
+        self.erase_block(location)?;
+        let mut result = Ok(());
+        stream(
+            self,
+            location.into(),
+            self.erasable_block_size(),
+            |l, chunk| {
+                if let Some(i) = chunk.iter().position(|&x| x != 0xff) {
+                    result = Err(Error::Verify { location: l + i as Location });
+                }
+                Ok(result.is_ok())
+            },
+        )?;
+        result
+    }
+    /// Erases exactly the erasable blocks that writing SIZE Byte at
+    /// LOCATION would touch.
+    fn erase_for_write(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<()> {
+        self.erase_range(&ErasableRange::covering(location, size)?)
+    }
+    /// Like erase_and_write_blocks, but fills the remainder of the last
+    /// block with PAD instead of leaving it erased.
+    /// SCRATCH is used to assemble the last block.
+    /// Note: SCRATCH.len() == erasable_block_size()
+    /// Returns the location after the last block written.
+    fn erase_and_write_padded(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+        pad: u8,
+        scratch: &mut [u8],
+    ) -> Result<ErasableLocation> {
+        let erasable_block_size = self.erasable_block_size();
+        if scratch.len() != erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let (body, tail) =
+            buf.split_at(buf.len() - buf.len() % erasable_block_size);
+        self.erase_and_write_blocks(location, body)?;
+        let location = location.advance(body.len())?;
+        if tail.is_empty() {
+            return Ok(location);
         }
+        let (data, remainder) = scratch.split_at_mut(tail.len());
+        data.copy_from_slice(tail);
+        remainder.fill(pad);
+        self.erase_and_write_block(location, scratch)?;
+        location.advance(erasable_block_size)
     }
-
-    impl FlashAlign for FlashImage<'_> {
-        fn erasable_block_size(&self) -> usize {
-            self.erasable_block_size
+    /// Sets the Byte at CHECKSUM_OFFSET in RANGE such that all the Byte in
+    /// RANGE add up to 0 (modulo 256), as AMD headers require, and returns
+    /// it.
+    /// SCRATCH is used to rewrite the block that contains the checksum.
+    /// Note: SCRATCH.len() == erasable_block_size()
+    fn finalize_checksum_byte(
+        &self,
+        range: &ErasableRange,
+        checksum_offset: usize,
+        scratch: &mut [u8],
+    ) -> Result<u8> {
+        let erasable_block_size = self.erasable_block_size();
+        if checksum_offset >= range.capacity()
+            || scratch.len() != erasable_block_size
+        {
+            return Err(Error::Programmer);
         }
+        let mut sum = 0u8;
+        stream(self, range.beginning.into(), range.capacity(), |_, chunk| {
+            sum = chunk.iter().fold(sum, |sum, &x| sum.wrapping_add(x));
+            Ok(true)
+        })?;
+        let offset = checksum_offset % erasable_block_size;
+        let location = range.beginning.advance(checksum_offset - offset)?;
+        self.read_erasable_block(location, scratch)?;
+        let checksum = scratch[offset].wrapping_sub(sum);
+        scratch[offset] = checksum;
+        self.erase_and_write_block(location, scratch)?;
+        Ok(checksum)
     }
-    impl FlashWrite for FlashImage<'_> {
-        fn read_erasable_block(
-            &self,
-            location: ErasableLocation,
-            buffer: &mut [u8],
-        ) -> Result<()> {
-            let erasable_block_size = self.erasable_block_size();
-            let location: Location = location.into();
-            let buf = self.buf.borrow();
+    /// Replaces the Byte at OFFSET in the erasable block at LOCATION with
+    /// DATA, keeping the rest of the block.
+    /// DATA has to fit into the block (otherwise returns Error::Overflow).
+    /// SCRATCH is used to rewrite the block.
+    /// Note: SCRATCH.len() == erasable_block_size()
+    fn patch(
+        &self,
+        location: ErasableLocation,
+        offset: usize,
+        data: &[u8],
+        scratch: &mut [u8],
+    ) -> Result<()> {
+        let erasable_block_size = self.erasable_block_size();
+        if scratch.len() != erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let end = offset
+            .checked_add(data.len())
+            .filter(|&end| end <= erasable_block_size)
+            .ok_or(Error::Overflow)?;
+        self.read_erasable_block(location, scratch)?;
+        scratch[offset..end].copy_from_slice(data);
+        self.erase_and_write_block(location, scratch)
+    }
+    /// Copies the contents of SRC to the erasable blocks starting at DST,
+    /// one block at a time, erasing each block before writing it.
+    /// SCRATCH holds the block being copied.
+    /// Note: SCRATCH.len() == erasable_block_size()
+    /// Returns Error::Programmer if the destination overlaps SRC, since
+    /// NOR flash cannot copy in place.
+    fn copy_range(
+        &self,
+        src: &ErasableRange,
+        dst: ErasableLocation,
+        scratch: &mut [u8],
+    ) -> Result<()> {
+        if scratch.len() != self.erasable_block_size() {
+            return Err(Error::Programmer);
+        }
+        let dst = ErasableRange::new(dst, dst.advance(src.capacity())?);
+        if dst.intersect(src).is_some() {
+            return Err(Error::Programmer);
+        }
+        for (from, to) in src.blocks().zip(dst.blocks()) {
+            self.read_erasable_block(from, scratch)?;
+            self.erase_and_write_block(to, scratch)?;
+        }
+        Ok(())
+    }
+}
+
+// Since all the methods take &self, a shared reference to a flash chip
+// can be used as a flash chip, too--for example to pass the same chip to
+// several wrappers (like ChainFlash or Journaling) at once.
+// Every method that an implementation can override is forwarded, so that
+// the reference behaves exactly like the chip (a test checks that new ones
+// are forwarded, too).
+
+impl<F: FlashRead + ?Sized> FlashRead for &F {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        (**self).read_exact(beginning, buffer)
+    }
+    fn capacity(&self) -> Option<usize> {
+        (**self).capacity()
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [core::mem::MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        (**self).read_exact_uninit(beginning, buffer)
+    }
+    fn read_exact_checked(
+        &self,
+        beginning: Location,
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        (**self).read_exact_checked(beginning, buffer)
+    }
+    fn verify_against(
+        &self,
+        beginning: Location,
+        reference: &[u8],
+    ) -> Result<()> {
+        (**self).verify_against(beginning, reference)
+    }
+    fn first_diff_offset(
+        &self,
+        beginning: Location,
+        reference: &[u8],
+    ) -> Result<Option<usize>> {
+        (**self).first_diff_offset(beginning, reference)
+    }
+    fn write_is_clean(
+        &self,
+        beginning: Location,
+        buffer: &[u8],
+    ) -> Result<bool> {
+        (**self).write_is_clean(beginning, buffer)
+    }
+    fn count_erases_for(
+        &self,
+        start: ErasableLocation,
+        data: &[u8],
+    ) -> Result<usize> {
+        (**self).count_erases_for(start, data)
+    }
+    #[cfg(feature = "std")]
+    fn diff_ranges(
+        &self,
+        start: ErasableLocation,
+        reference: &[u8],
+    ) -> Result<Vec<ErasableRange>> {
+        (**self).diff_ranges(start, reference)
+    }
+    fn verify_padding(
+        &self,
+        beginning: ErasableLocation,
+        data_size: usize,
+        pad: u8,
+    ) -> Result<()> {
+        (**self).verify_padding(beginning, data_size, pad)
+    }
+    fn first_erased_block(
+        &self,
+        range: &ErasableRange,
+    ) -> Result<Option<ErasableLocation>> {
+        (**self).first_erased_block(range)
+    }
+    fn region_hash(&self, range: &ErasableRange) -> Result<u64> {
+        (**self).region_hash(range)
+    }
+    fn read_tail(
+        &self,
+        range: &ErasableRange,
+        blocks: usize,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        (**self).read_tail(range, blocks, buf)
+    }
+    fn crc32(&self, range: &ErasableRange) -> Result<u32> {
+        (**self).crc32(range)
+    }
+    #[cfg(feature = "std")]
+    fn sha256(&self, range: &ErasableRange) -> Result<[u8; 32]> {
+        (**self).sha256(range)
+    }
+    fn is_blank(&self, location: ErasableLocation) -> Result<bool> {
+        (**self).is_blank(location)
+    }
+    fn is_range_blank(&self, range: &ErasableRange) -> Result<bool> {
+        (**self).is_range_blank(range)
+    }
+    fn block_uniform_byte(
+        &self,
+        location: ErasableLocation,
+    ) -> Result<Option<u8>> {
+        (**self).block_uniform_byte(location)
+    }
+    #[cfg(feature = "std")]
+    fn read_length_prefixed(
+        &self,
+        at: Location,
+        len_bytes: usize,
+        little_endian: bool,
+        max_len: usize,
+    ) -> Result<Vec<u8>> {
+        (**self).read_length_prefixed(at, len_bytes, little_endian, max_len)
+    }
+}
+
+impl<F: FlashAlign + ?Sized> FlashAlign for &F {
+    fn erasable_block_size(&self) -> usize {
+        (**self).erasable_block_size()
+    }
+    fn erasable_block_mask(&self) -> u32 {
+        (**self).erasable_block_mask()
+    }
+    fn preferred_read_chunk(&self) -> usize {
+        (**self).preferred_read_chunk()
+    }
+    fn is_aligned(&self, location: Location) -> bool {
+        (**self).is_aligned(location)
+    }
+    fn erasable_location(
+        &self,
+        location: Location,
+    ) -> Option<ErasableLocation> {
+        (**self).erasable_location(location)
+    }
+    fn block_location(&self, index: u32) -> Result<ErasableLocation> {
+        (**self).block_location(index)
+    }
+    fn location(
+        &self,
+        erasable_location: ErasableLocation,
+    ) -> Result<Location> {
+        (**self).location(erasable_location)
+    }
+    fn footprint(&self, sizes: &[usize]) -> Result<usize> {
+        (**self).footprint(sizes)
+    }
+}
+
+impl<F: FlashWrite + ?Sized> FlashWrite for &F {
+    fn read_erasable_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        (**self).read_erasable_block(location, buffer)
+    }
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        (**self).erase_block(location)
+    }
+    fn lazy_erase(&self) -> bool {
+        (**self).lazy_erase()
+    }
+    fn erase_if_needed(&self, location: ErasableLocation) -> Result<bool> {
+        (**self).erase_if_needed(location)
+    }
+    fn coarse_erase_sizes(&self) -> &[usize] {
+        (**self).coarse_erase_sizes()
+    }
+    fn erase_coarse(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<()> {
+        (**self).erase_coarse(location, size)
+    }
+    fn erase_range(&self, range: &ErasableRange) -> Result<()> {
+        (**self).erase_range(range)
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        (**self).erase_and_write_block(location, buffer)
+    }
+    fn write_erased_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        (**self).write_erased_block(location, buffer)
+    }
+    fn write_block_opt(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        (**self).write_block_opt(location, buffer)
+    }
+    fn erase_and_write_blocks(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+    ) -> Result<()> {
+        (**self).erase_and_write_blocks(location, buf)
+    }
+    fn write_checked(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+    ) -> Result<()> {
+        (**self).write_checked(location, buf)
+    }
+    fn write_verified(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+    ) -> Result<()> {
+        (**self).write_verified(location, buf)
+    }
+    fn erase_verified(&self, location: ErasableLocation) -> Result<()> {
+        (**self).erase_verified(location)
+    }
+    fn erase_for_write(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<()> {
+        (**self).erase_for_write(location, size)
+    }
+    fn erase_and_write_padded(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
+        pad: u8,
+        scratch: &mut [u8],
+    ) -> Result<ErasableLocation> {
+        (**self).erase_and_write_padded(location, buf, pad, scratch)
+    }
+    fn finalize_checksum_byte(
+        &self,
+        range: &ErasableRange,
+        checksum_offset: usize,
+        scratch: &mut [u8],
+    ) -> Result<u8> {
+        (**self).finalize_checksum_byte(range, checksum_offset, scratch)
+    }
+    fn patch(
+        &self,
+        location: ErasableLocation,
+        offset: usize,
+        data: &[u8],
+        scratch: &mut [u8],
+    ) -> Result<()> {
+        (**self).patch(location, offset, data, scratch)
+    }
+    fn copy_range(
+        &self,
+        src: &ErasableRange,
+        dst: ErasableLocation,
+        scratch: &mut [u8],
+    ) -> Result<()> {
+        (**self).copy_range(src, dst, scratch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+    const KIB: usize = 1024; // B
+    const ERASABLE_BLOCK_SIZE: usize = 128 * KIB;
+
+    pub(crate) struct FlashImage<'a> {
+        buf: RefCell<&'a mut [u8]>,
+        erasable_block_size: usize,
+        coarse_erase_sizes: &'static [usize],
+        preferred_read_chunk: Option<usize>,
+        /// Location of a byte with a bit that erasing fails to set
+        stuck_bit: Option<Location>,
+        /// Size of each read operation, in order
+        pub(crate) reads: RefCell<Vec<usize>>,
+        /// Size of each erase operation, in order
+        pub(crate) erases: RefCell<Vec<usize>>,
+    }
+
+    impl<'a> FlashImage<'a> {
+        pub fn new(buf: &'a mut [u8]) -> Self {
+            Self::with_geometry(buf, ERASABLE_BLOCK_SIZE, &[])
+        }
+        pub fn with_geometry(
+            buf: &'a mut [u8],
+            erasable_block_size: usize,
+            coarse_erase_sizes: &'static [usize],
+        ) -> Self {
+            Self {
+                buf: RefCell::new(buf),
+                erasable_block_size,
+                coarse_erase_sizes,
+                preferred_read_chunk: None,
+                stuck_bit: None,
+                reads: RefCell::new(Vec::new()),
+                erases: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl FlashRead for FlashImage<'_> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            let len = buffer.len();
+            self.reads.borrow_mut().push(len);
+            let buf = self.buf.borrow();
+            let block = &buf[location as usize..];
+            let block = &block[0..len];
+            buffer[..].copy_from_slice(block);
+            Ok(())
+        }
+        fn capacity(&self) -> Option<usize> {
+            Some(self.buf.borrow().len())
+        }
+    }
+
+    impl FlashAlign for FlashImage<'_> {
+        fn erasable_block_size(&self) -> usize {
+            self.erasable_block_size
+        }
+        fn preferred_read_chunk(&self) -> usize {
+            self.preferred_read_chunk.unwrap_or(self.erasable_block_size)
+        }
+    }
+    impl FlashWrite for FlashImage<'_> {
+        fn read_erasable_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            let erasable_block_size = self.erasable_block_size();
+            let location: Location = location.into();
+            let buf = self.buf.borrow();
             let block = &buf
                 [location as usize..(location as usize + erasable_block_size)];
             if buffer.len() != erasable_block_size {
@@ -252,6 +1612,28 @@ mod tests {
             let block = &mut buf[location as usize
                 ..(location as usize + self.erasable_block_size())];
             block.fill(0xff);
+            if let Some(stuck_bit) = self.stuck_bit {
+                if let Some(x) = block.get_mut(
+                    (stuck_bit as usize).wrapping_sub(location as usize),
+                ) {
+                    *x &= !1;
+                }
+            }
+            self.erases.borrow_mut().push(self.erasable_block_size());
+            Ok(())
+        }
+        fn coarse_erase_sizes(&self) -> &[usize] {
+            self.coarse_erase_sizes
+        }
+        fn erase_coarse(
+            &self,
+            location: ErasableLocation,
+            size: usize,
+        ) -> Result<()> {
+            let location: Location = location.into();
+            let mut buf = self.buf.borrow_mut();
+            buf[location as usize..(location as usize + size)].fill(0xff);
+            self.erases.borrow_mut().push(size);
             Ok(())
         }
         fn erase_and_write_block(
@@ -263,7 +1645,22 @@ mod tests {
             let mut buf = self.buf.borrow_mut();
             let block = &mut buf[location as usize
                 ..(location as usize + self.erasable_block_size())];
-            block.copy_from_slice(&buffer[..]);
+            let (data, remainder) = block.split_at_mut(buffer.len());
+            data.copy_from_slice(buffer);
+            remainder.fill(0xff);
+            Ok(())
+        }
+        /// Like NOR flash, can only clear bits.
+        fn write_erased_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            let location: Location = location.into();
+            let mut buf = self.buf.borrow_mut();
+            for (x, &y) in buf[location as usize..].iter_mut().zip(buffer) {
+                *x &= y;
+            }
             Ok(())
         }
     }
@@ -290,4 +1687,1641 @@ mod tests {
         assert_eq!(buf, [2u8; ERASABLE_BLOCK_SIZE]);
         Ok(())
     }
+
+    #[test]
+    fn read_exact_checked_past_end() -> Result<()> {
+        let mut storage = [0xFFu8; 2 * ERASABLE_BLOCK_SIZE];
+        let flash_image = FlashImage::new(&mut storage[..]);
+        let mut buf = [0u8; 16];
+        let last = (2 * ERASABLE_BLOCK_SIZE - buf.len()) as Location;
+        flash_image.read_exact_checked(last, &mut buf)?;
+        assert!(matches!(
+            flash_image.read_exact_checked(last + 1, &mut buf),
+            Err(Error::OutOfBounds { location }) if location == last + 1
+        ));
+        assert!(matches!(
+            flash_image.read_exact_checked(Location::MAX, &mut buf),
+            Err(Error::OutOfBounds { location: Location::MAX })
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn dump_to_writer_uses_preferred_read_chunk() -> Result<()> {
+        let mut storage = [0u8; 2 * ERASABLE_BLOCK_SIZE];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let expected = storage.to_vec();
+        let mut flash_image = FlashImage::new(&mut storage[..]);
+        flash_image.preferred_read_chunk = Some(4 * KIB);
+        let mut out = Vec::new();
+        flash_image.dump_to_writer(0x100, 10 * KIB, &mut out)?;
+        assert_eq!(out, &expected[0x100..0x100 + 10 * KIB]);
+        assert_eq!(*flash_image.reads.borrow(), [4 * KIB, 4 * KIB, 2 * KIB]);
+
+        // A zero chunk size must not make the loop spin.
+        flash_image.preferred_read_chunk = Some(0);
+        flash_image.reads.borrow_mut().clear();
+        let mut out = Vec::new();
+        flash_image.dump_to_writer(0x100, 3, &mut out)?;
+        assert_eq!(out, &expected[0x100..0x103]);
+        assert_eq!(*flash_image.reads.borrow(), [1, 1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn erase_range_coalesces() -> Result<()> {
+        let mut storage = [0u8; 256 * KIB];
+        let flash_image = FlashImage::with_geometry(
+            &mut storage[..],
+            4 * KIB,
+            &[64 * KIB, 32 * KIB],
+        );
+        let beginning = flash_image.erasable_location(64 * KIB as u32).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(64 * KIB)?);
+        flash_image.erase_range(&range)?;
+        assert_eq!(*flash_image.erases.borrow(), [64 * KIB]);
+
+        flash_image.erases.borrow_mut().clear();
+        let beginning = flash_image.erasable_location(28 * KIB as u32).unwrap();
+        let end = flash_image.erasable_location(140 * KIB as u32).unwrap();
+        flash_image.erase_range(&ErasableRange::new(beginning, end))?;
+        assert_eq!(
+            *flash_image.erases.borrow(),
+            [4 * KIB, 32 * KIB, 64 * KIB, 4 * KIB, 4 * KIB, 4 * KIB]
+        );
+        let mut buf = [0u8; 140 * KIB];
+        flash_image.read_exact(0, &mut buf)?;
+        assert!(buf[..28 * KIB].iter().all(|&b| b == 0));
+        assert!(buf[28 * KIB..].iter().all(|&b| b == 0xff));
+        flash_image.read_exact(140 * KIB as u32, &mut buf[..4 * KIB])?;
+        assert!(buf[..4 * KIB].iter().all(|&b| b == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn first_diff_offset() -> Result<()> {
+        let mut storage = [0u8; 4 * ERASABLE_BLOCK_SIZE];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = (i % 253) as u8;
+        }
+        let mut reference = [0u8; 2 * ERASABLE_BLOCK_SIZE];
+        reference.copy_from_slice(
+            &storage[ERASABLE_BLOCK_SIZE..3 * ERASABLE_BLOCK_SIZE],
+        );
+        let flash_image = FlashImage::new(&mut storage[..]);
+        let beginning = ERASABLE_BLOCK_SIZE as Location;
+        assert_eq!(flash_image.first_diff_offset(beginning, &reference)?, None);
+        assert_eq!(flash_image.first_diff_offset(beginning, &[])?, None);
+
+        let middle = ERASABLE_BLOCK_SIZE + ERASABLE_BLOCK_SIZE / 2 + 3;
+        reference[middle] ^= 0x10;
+        reference[middle + 1] ^= 0x10;
+        assert_eq!(
+            flash_image.first_diff_offset(beginning, &reference)?,
+            Some(ERASABLE_BLOCK_SIZE + middle)
+        );
+        assert_eq!(
+            flash_image.first_diff_offset(beginning + 1, &reference[1..])?,
+            Some(ERASABLE_BLOCK_SIZE + middle)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_against_reference() -> Result<()> {
+        let mut storage = [0u8; 2 * ERASABLE_BLOCK_SIZE];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = (i % 253) as u8;
+        }
+        let mut reference = [0u8; ERASABLE_BLOCK_SIZE + 1000];
+        let len = reference.len();
+        reference.copy_from_slice(&storage[0x10..0x10 + len]);
+        let flash_image = FlashImage::new(&mut storage[..]);
+        flash_image.verify_against(0x10, &reference)?;
+        flash_image.verify_against(0x10, &[])?;
+
+        reference[0] ^= 1;
+        assert!(matches!(
+            flash_image.verify_against(0x10, &reference),
+            Err(Error::Verify { location: 0x10 })
+        ));
+        reference[0] ^= 1;
+        reference[ERASABLE_BLOCK_SIZE + 999] ^= 0x80;
+        assert!(matches!(
+            flash_image.verify_against(0x10, &reference),
+            Err(Error::Verify { location })
+                if location as usize == 0x10 + ERASABLE_BLOCK_SIZE + 999
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn erase_and_write_padded_tail() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash_image =
+            FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let beginning = flash_image.erasable_location(0).unwrap();
+        let data = [0x42u8; 4 * KIB + 100];
+        let mut scratch = [0u8; 4 * KIB];
+        let end = flash_image.erase_and_write_padded(
+            beginning,
+            &data,
+            0x00,
+            &mut scratch,
+        )?;
+        assert_eq!(Location::from(end), 8 * KIB as Location);
+        let end = flash_image.erase_and_write_padded(
+            end,
+            &data[..4 * KIB],
+            0x00,
+            &mut scratch,
+        )?;
+        assert_eq!(Location::from(end), 12 * KIB as Location);
+        let mut buf = [0xffu8; 12 * KIB];
+        flash_image.read_exact(0, &mut buf)?;
+        assert!(buf[..data.len()].iter().all(|&x| x == 0x42));
+        assert!(buf[data.len()..8 * KIB].iter().all(|&x| x == 0x00));
+        assert!(buf[8 * KIB..].iter().all(|&x| x == 0x42));
+        Ok(())
+    }
+
+    #[test]
+    fn finalize_checksum_byte() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        let flash_image =
+            FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let beginning =
+            flash_image.erasable_location(4 * KIB as Location).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(8 * KIB)?);
+        let mut header = [0u8; 4 * KIB + 0x20];
+        for (i, x) in header.iter_mut().enumerate() {
+            *x = (i * 7) as u8;
+        }
+        flash_image.erase_and_write_blocks(beginning, &header)?;
+        let mut scratch = [0u8; 4 * KIB];
+        let checksum = flash_image.finalize_checksum_byte(
+            &range,
+            4 * KIB + 0x10,
+            &mut scratch,
+        )?;
+        let mut buf = [0u8; 8 * KIB];
+        flash_image.read_exact(4 * KIB as Location, &mut buf)?;
+        assert_eq!(buf[4 * KIB + 0x10], checksum);
+        assert_eq!(buf.iter().fold(0u8, |sum, &x| sum.wrapping_add(x)), 0);
+        // Everything else is unchanged
+        buf[4 * KIB + 0x10] = header[4 * KIB + 0x10];
+        assert_eq!(buf[..header.len()], header);
+        assert!(buf[header.len()..].iter().all(|&x| x == 0xff));
+
+        // Idempotent
+        assert_eq!(
+            flash_image.finalize_checksum_byte(
+                &range,
+                4 * KIB + 0x10,
+                &mut scratch
+            )?,
+            checksum
+        );
+        assert!(matches!(
+            flash_image.finalize_checksum_byte(&range, 8 * KIB, &mut scratch),
+            Err(Error::Programmer)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn first_erased_block() -> Result<()> {
+        let mut storage = [0u8; 32 * KIB];
+        let flash_image =
+            FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let beginning = flash_image.erasable_location(0).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(32 * KIB)?);
+        assert!(flash_image.first_erased_block(&range)?.is_none());
+        flash_image.erase_range(&range)?;
+        flash_image.erase_and_write_blocks(beginning, &[0u8; 8 * KIB + 1])?;
+        let location = flash_image.first_erased_block(&range)?.unwrap();
+        assert_eq!(Location::from(location), 12 * KIB as Location);
+        let upper = ErasableRange::new(location, range.end);
+        let location = flash_image.first_erased_block(&upper)?.unwrap();
+        assert_eq!(Location::from(location), 12 * KIB as Location);
+        Ok(())
+    }
+
+    #[test]
+    fn erase_verified_catches_stuck_bit() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let mut flash_image =
+            FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        flash_image.stuck_bit = Some(5 * KIB as Location + 7);
+        let good = flash_image.erasable_location(0).unwrap();
+        flash_image.erase_verified(good)?;
+        let bad = flash_image.erasable_location(4 * KIB as u32).unwrap();
+        assert!(matches!(
+            flash_image.erase_verified(bad),
+            Err(Error::Verify { location }) if location as usize == 5 * KIB + 7
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn erase_for_write_covers_exactly() -> Result<()> {
+        let mut storage = [0u8; 32 * KIB];
+        let flash_image =
+            FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let beginning = flash_image.erasable_location(4 * KIB as u32).unwrap();
+        flash_image.erase_for_write(beginning, 10 * KIB)?;
+        assert_eq!(*flash_image.erases.borrow(), [4 * KIB; 3]);
+        let mut buf = [0u8; 32 * KIB];
+        flash_image.read_exact(0, &mut buf)?;
+        assert!(buf[..4 * KIB].iter().all(|&x| x == 0));
+        assert!(buf[4 * KIB..16 * KIB].iter().all(|&x| x == 0xff));
+        assert!(buf[16 * KIB..].iter().all(|&x| x == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn write_is_clean() -> Result<()> {
+        let mut storage = [0xffu8; 2 * KIB];
+        storage[KIB..].fill(0b1010_1010);
+        let flash_image = FlashImage::new(&mut storage[..]);
+        assert!(flash_image.write_is_clean(0, &[0x12; KIB])?);
+        assert!(!flash_image.write_is_clean(0, &[0x12; 2 * KIB])?);
+        assert!(flash_image.write_is_clean(KIB as u32, &[0b1000_0010; 16])?);
+        assert!(flash_image.write_is_clean(KIB as u32, &[0b1010_1010; 16])?);
+        assert!(!flash_image.write_is_clean(KIB as u32, &[0b0101_0101; 16])?);
+        let mut buf = [0xffu8; 2 * KIB];
+        buf[KIB..].fill(0b1010_1010);
+        assert!(flash_image.write_is_clean(0, &buf)?);
+        buf[2 * KIB - 1] = 0xff;
+        assert!(!flash_image.write_is_clean(0, &buf)?);
+        Ok(())
+    }
+
+    #[test]
+    fn error_codes() {
+        let errors = [
+            Error::io_caused_by(Error::Size),
+            Error::Alignment,
+            Error::Programmer,
+            Error::Size,
+            Error::Overflow,
+            Error::OutOfBounds { location: 0 },
+            Error::Verify { location: 0 },
+        ];
+        let codes = errors.map(|e| e.code());
+        assert_eq!(codes, [1, 2, 3, 4, 5, 6, 7]);
+        for (i, a) in codes.iter().enumerate() {
+            assert!(!codes[i + 1..].contains(a));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn io_error_source() {
+        use std::error::Error as _;
+        let error = Error::from(std::io::Error::from(
+            std::io::ErrorKind::PermissionDenied,
+        ));
+        assert_eq!(error.code(), 1);
+        assert!(error.to_string().contains("permission denied"));
+        let source = error.source().unwrap();
+        let source = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.kind(), std::io::ErrorKind::PermissionDenied);
+
+        let error = Error::io_caused_by(Error::Verify { location: 0x10 });
+        assert!(error
+            .to_string()
+            .contains("verification failed at location 0x10"));
+    }
+
+    #[test]
+    fn verify_padding() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash_image =
+            FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let beginning = flash_image.erasable_location(4 * KIB as u32).unwrap();
+        let data = [0x42u8; 5 * KIB];
+        let mut scratch = [0u8; 4 * KIB];
+        flash_image.erase_and_write_padded(
+            beginning,
+            &data,
+            0x5a,
+            &mut scratch,
+        )?;
+        flash_image.verify_padding(beginning, data.len(), 0x5a)?;
+        flash_image.verify_padding(beginning, 8 * KIB, 0x5a)?;
+        assert!(matches!(
+            flash_image.verify_padding(beginning, data.len(), 0xff),
+            Err(Error::Verify { location }) if location as usize == 9 * KIB
+        ));
+        assert!(matches!(
+            flash_image.verify_padding(beginning, data.len() - 1, 0x5a),
+            Err(Error::Verify { location }) if location as usize == 9 * KIB - 1
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn error_from_try_from_int_error() {
+        fn narrow(x: u64) -> Result<u32> {
+            Ok(x.try_into()?)
+        }
+        assert_eq!(narrow(5).unwrap(), 5);
+        assert!(matches!(narrow(1 << 32), Err(Error::Overflow)));
+    }
+
+    #[test]
+    fn footprint_rounds_each_entry() -> Result<()> {
+        let flash_image = FlashImage::new(&mut []);
+        assert_eq!(flash_image.footprint(&[])?, 0);
+        assert_eq!(
+            flash_image.footprint(&[1, ERASABLE_BLOCK_SIZE, 1])?,
+            3 * ERASABLE_BLOCK_SIZE
+        );
+        assert_eq!(
+            flash_image.footprint(&[ERASABLE_BLOCK_SIZE + 1, 0])?,
+            2 * ERASABLE_BLOCK_SIZE
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn range_from_unordered() {
+        let flash_image = FlashImage::new(&mut []);
+        let a = flash_image.erasable_location(0).unwrap();
+        let b = a.advance(2 * ERASABLE_BLOCK_SIZE).unwrap();
+        let ordered = ErasableRange::from_unordered(a, b);
+        let reversed = ErasableRange::from_unordered(b, a);
+        assert_eq!(Location::from(ordered.beginning), 0);
+        assert_eq!(Location::from(ordered.end), Location::from(b));
+        assert_eq!(
+            Location::from(reversed.beginning),
+            Location::from(ordered.beginning)
+        );
+        assert_eq!(Location::from(reversed.end), Location::from(ordered.end));
+        let empty = ErasableRange::from_unordered(b, b);
+        assert_eq!(empty.capacity(), 0);
+    }
+
+    #[test]
+    fn range_try_new() -> Result<()> {
+        let flash_image = FlashImage::new(&mut []);
+        let a = flash_image.erasable_location(0).unwrap();
+        let b = a.advance(ERASABLE_BLOCK_SIZE)?;
+        assert_eq!(
+            ErasableRange::try_new(a, b)?.capacity(),
+            ERASABLE_BLOCK_SIZE
+        );
+        assert_eq!(ErasableRange::try_new(a, a)?.capacity(), 0);
+        assert!(matches!(ErasableRange::try_new(b, a), Err(Error::Programmer)));
+        Ok(())
+    }
+
+    #[test]
+    fn range_take_exact() -> Result<()> {
+        let flash_image = FlashImage::new(&mut []);
+        let beginning = flash_image.erasable_location(0).unwrap();
+        let mut range = ErasableRange::new(
+            beginning,
+            beginning.advance(2 * ERASABLE_BLOCK_SIZE)?,
+        );
+        let a = range.take_exact(ERASABLE_BLOCK_SIZE)?;
+        assert_eq!(a.capacity(), ERASABLE_BLOCK_SIZE);
+        assert_eq!(Location::from(range.beginning), Location::from(a.end));
+        assert!(matches!(range.take_exact(1), Err(Error::Alignment)));
+        assert!(matches!(
+            range.take_exact(2 * ERASABLE_BLOCK_SIZE),
+            Err(Error::Size)
+        ));
+        assert_eq!(range.capacity(), ERASABLE_BLOCK_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn image_size_of_ranges() {
+        let flash_image = FlashImage::new(&mut []);
+        let range = |beginning: usize, end: usize| {
+            ErasableRange::new(
+                flash_image.erasable_location(beginning as Location).unwrap(),
+                flash_image.erasable_location(end as Location).unwrap(),
+            )
+        };
+        assert_eq!(required_image_size(&[]), 0);
+        let disjoint = [
+            range(3 * ERASABLE_BLOCK_SIZE, 4 * ERASABLE_BLOCK_SIZE),
+            range(0, ERASABLE_BLOCK_SIZE),
+        ];
+        assert_eq!(required_image_size(&disjoint), 4 * ERASABLE_BLOCK_SIZE);
+        let overlapping = [
+            range(0, 3 * ERASABLE_BLOCK_SIZE),
+            range(ERASABLE_BLOCK_SIZE, 2 * ERASABLE_BLOCK_SIZE),
+        ];
+        assert_eq!(required_image_size(&overlapping), 3 * ERASABLE_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn ranges_validate_within() {
+        let flash_image = FlashImage::new(&mut []);
+        let range = |beginning: usize, end: usize| {
+            ErasableRange::new(
+                flash_image.erasable_location(beginning as Location).unwrap(),
+                flash_image.erasable_location(end as Location).unwrap(),
+            )
+        };
+        let bounds = range(ERASABLE_BLOCK_SIZE, 4 * ERASABLE_BLOCK_SIZE);
+        let inside = [
+            range(3 * ERASABLE_BLOCK_SIZE, 4 * ERASABLE_BLOCK_SIZE),
+            range(ERASABLE_BLOCK_SIZE, 2 * ERASABLE_BLOCK_SIZE),
+            range(2 * ERASABLE_BLOCK_SIZE, 2 * ERASABLE_BLOCK_SIZE),
+        ];
+        assert!(validate_within(&inside, &bounds).is_ok());
+        assert!(validate_within(&[], &bounds).is_ok());
+        let outside = [
+            range(ERASABLE_BLOCK_SIZE, 2 * ERASABLE_BLOCK_SIZE),
+            range(3 * ERASABLE_BLOCK_SIZE, 5 * ERASABLE_BLOCK_SIZE),
+        ];
+        assert!(matches!(
+            validate_within(&outside, &bounds),
+            Err(Error::OutOfBounds { location })
+                if location as usize == 3 * ERASABLE_BLOCK_SIZE
+        ));
+        let below = [range(0, 2 * ERASABLE_BLOCK_SIZE)];
+        assert!(matches!(
+            validate_within(&below, &bounds),
+            Err(Error::OutOfBounds { location: 0 })
+        ));
+        let overlapping = [
+            range(ERASABLE_BLOCK_SIZE, 3 * ERASABLE_BLOCK_SIZE),
+            range(2 * ERASABLE_BLOCK_SIZE, 4 * ERASABLE_BLOCK_SIZE),
+        ];
+        assert!(matches!(
+            validate_within(&overlapping, &bounds),
+            Err(Error::Programmer)
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn ranges_lint_layout() {
+        let flash_image = FlashImage::new(&mut []);
+        let range = |beginning: usize, end: usize| {
+            ErasableRange::new(
+                flash_image.erasable_location(beginning as Location).unwrap(),
+                flash_image.erasable_location(end as Location).unwrap(),
+            )
+        };
+        let bounds = range(ERASABLE_BLOCK_SIZE, 4 * ERASABLE_BLOCK_SIZE);
+        let fine = [
+            range(3 * ERASABLE_BLOCK_SIZE, 4 * ERASABLE_BLOCK_SIZE),
+            range(ERASABLE_BLOCK_SIZE, 3 * ERASABLE_BLOCK_SIZE),
+        ];
+        assert_eq!(lint_layout(&fine, &bounds), []);
+        let layout = [
+            range(ERASABLE_BLOCK_SIZE, 3 * ERASABLE_BLOCK_SIZE),
+            range(3 * ERASABLE_BLOCK_SIZE, 5 * ERASABLE_BLOCK_SIZE),
+            range(2 * ERASABLE_BLOCK_SIZE, 3 * ERASABLE_BLOCK_SIZE),
+        ];
+        assert_eq!(
+            lint_layout(&layout, &bounds),
+            [
+                LayoutProblem::Overlap { a: 0, b: 2 },
+                LayoutProblem::OutOfBounds { index: 1 },
+            ]
+        );
+        assert!(validate_within(&layout, &bounds).is_err());
+    }
+
+    #[test]
+    fn range_tile() -> Result<()> {
+        let flash_image = FlashImage::new(&mut []);
+        let base = flash_image
+            .erasable_location(ERASABLE_BLOCK_SIZE as Location)
+            .unwrap();
+        let range = ErasableRange::tile(base, 2 * ERASABLE_BLOCK_SIZE, 3)?;
+        assert_eq!(Location::from(range.beginning), Location::from(base));
+        assert_eq!(range.capacity(), 6 * ERASABLE_BLOCK_SIZE);
+        assert_eq!(
+            ErasableRange::tile(base, ERASABLE_BLOCK_SIZE, 0)?.capacity(),
+            0
+        );
+        assert!(matches!(
+            ErasableRange::tile(base, ERASABLE_BLOCK_SIZE, usize::MAX),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            ErasableRange::tile(base, ERASABLE_BLOCK_SIZE, 0x8000),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            ErasableRange::tile(base, 1, 2),
+            Err(Error::Alignment)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn range_midpoint() {
+        let flash_image = FlashImage::new(&mut []);
+        let beginning = flash_image
+            .erasable_location(ERASABLE_BLOCK_SIZE as Location)
+            .unwrap();
+        let even = ErasableRange::new(
+            beginning,
+            beginning.advance(4 * ERASABLE_BLOCK_SIZE).unwrap(),
+        );
+        let midpoint = Location::from(even.midpoint());
+        assert_eq!(midpoint, 3 * ERASABLE_BLOCK_SIZE as Location);
+        let odd = ErasableRange::new(
+            beginning,
+            beginning.advance(3 * ERASABLE_BLOCK_SIZE).unwrap(),
+        );
+        let midpoint = Location::from(odd.midpoint());
+        assert!(flash_image.is_aligned(midpoint));
+        assert_eq!(midpoint, 2 * ERASABLE_BLOCK_SIZE as Location);
+        let (a, b) = odd.split_at_midpoint();
+        assert_eq!(Location::from(a.beginning), Location::from(odd.beginning));
+        assert_eq!(Location::from(a.end), midpoint);
+        assert_eq!(Location::from(b.beginning), midpoint);
+        assert_eq!(Location::from(b.end), Location::from(odd.end));
+        assert_eq!(a.capacity(), ERASABLE_BLOCK_SIZE);
+        assert_eq!(b.capacity(), 2 * ERASABLE_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn footprint_overflow() {
+        let flash_image = FlashImage::new(&mut []);
+        assert!(matches!(
+            flash_image.footprint(&[usize::MAX]),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            flash_image.footprint(&[usize::MAX / 2 + 1, usize::MAX / 2 + 1]),
+            Err(Error::Overflow)
+        ));
+    }
+
+    struct LazyFlashImage<'a>(FlashImage<'a>);
+
+    impl FlashRead for LazyFlashImage<'_> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.0.read_exact(location, buffer)
+        }
+    }
+
+    impl FlashAlign for LazyFlashImage<'_> {
+        fn erasable_block_size(&self) -> usize {
+            self.0.erasable_block_size()
+        }
+    }
+
+    impl FlashWrite for LazyFlashImage<'_> {
+        fn lazy_erase(&self) -> bool {
+            true
+        }
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.0.erase_block(location)
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.0.erase_and_write_block(location, buffer)
+        }
+    }
+
+    #[test]
+    fn lazy_erase_skips_erased_blocks() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        storage[4 * KIB] = 0;
+        storage[12 * KIB + 1] = 0;
+        let flash = LazyFlashImage(FlashImage::with_geometry(
+            &mut storage,
+            4 * KIB,
+            &[],
+        ));
+        let beginning = flash.erasable_location(0).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(16 * KIB)?);
+        // Also makes sure that FlashWrite stays object-safe.
+        let dyn_flash: &dyn FlashWrite = &flash;
+        dyn_flash.erase_range(&range)?;
+        assert_eq!(*flash.0.erases.borrow(), [4 * KIB, 4 * KIB]);
+        assert!(flash.first_erased_block(&range)?.is_some());
+        let mut buf = [0u8; 16 * KIB];
+        flash.read_exact(0, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0xff));
+
+        assert!(!flash.erase_if_needed(beginning)?);
+        assert_eq!(flash.0.erases.borrow().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn eager_erase_erases_erased_blocks() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let beginning = flash.erasable_location(0).unwrap();
+        flash.erase_range(&ErasableRange::new(
+            beginning,
+            beginning.advance(16 * KIB)?,
+        ))?;
+        assert_eq!(flash.erases.borrow().len(), 4);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn range_split_at_alignment() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |x: usize| flash.erasable_location(x as Location).unwrap();
+        let range = ErasableRange::new(location(60 * KIB), location(132 * KIB));
+        let parts = range.split_at_alignment(block::Size::B64K)?;
+        let parts: Vec<_> = parts
+            .iter()
+            .map(|part| (Location::from(part.beginning), part.capacity()))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                (60 * KIB as Location, 4 * KIB),
+                (64 * KIB as Location, 64 * KIB),
+                (128 * KIB as Location, 4 * KIB)
+            ]
+        );
+
+        let range = ErasableRange::new(location(64 * KIB), location(128 * KIB));
+        assert_eq!(range.split_at_alignment(block::Size::B64K)?.len(), 1);
+        let range = ErasableRange::new(location(64 * KIB), location(64 * KIB));
+        assert!(range.split_at_alignment(block::Size::B64K)?.is_empty());
+        let flash = FlashImage::with_geometry(&mut storage, 8 * KIB, &[]);
+        let range = ErasableRange::new(
+            flash.erasable_location(0).unwrap(),
+            flash.erasable_location(64 * KIB as Location).unwrap(),
+        );
+        assert!(matches!(
+            range.split_at_alignment(block::Size::B12K),
+            Err(Error::Alignment)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn location_next_prev_block() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location = flash.erasable_location(8 * KIB as Location).unwrap();
+        assert_eq!(
+            Location::from(location.next_block()?),
+            12 * KIB as Location
+        );
+        assert_eq!(Location::from(location.prev_block()?), 4 * KIB as Location);
+        assert_eq!(
+            Location::from(location.next_block()?.prev_block()?),
+            Location::from(location)
+        );
+        let zero = flash.erasable_location(0).unwrap();
+        assert!(matches!(zero.prev_block(), Err(Error::Overflow)));
+        let last = flash
+            .erasable_location(Location::MAX & !(4 * KIB as Location - 1))
+            .unwrap();
+        assert!(matches!(last.next_block(), Err(Error::Overflow)));
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_length_prefixed() -> Result<()> {
+        let mut storage = [0xffu8; 4 * KIB];
+        storage[0x10..0x14].copy_from_slice(&5u32.to_le_bytes());
+        storage[0x14..0x19].copy_from_slice(b"hello");
+        storage[0x20..0x22].copy_from_slice(&3u16.to_be_bytes());
+        storage[0x22..0x25].copy_from_slice(b"abc");
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        assert_eq!(flash.read_length_prefixed(0x10, 4, true, 16)?, b"hello");
+        assert_eq!(flash.read_length_prefixed(0x20, 2, false, 16)?, b"abc");
+        assert!(matches!(
+            flash.read_length_prefixed(0x10, 4, true, 4),
+            Err(Error::Size)
+        ));
+        // Erased length field
+        assert!(matches!(
+            flash.read_length_prefixed(0x100, 4, true, 4 * KIB),
+            Err(Error::Size)
+        ));
+        assert!(matches!(
+            flash.read_length_prefixed(0x100, 2, true, usize::MAX),
+            Err(Error::OutOfBounds { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn count_erases_for() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        storage[4 * KIB] = 0x0f; // clean for 0x0f and 0x00, not for 0xf0
+        storage[8 * KIB] = 0x00;
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let start = flash.erasable_location(0).unwrap();
+        let mut data = [0xffu8; 14 * KIB];
+        assert_eq!(flash.count_erases_for(start, &data)?, 2);
+        data[4 * KIB] = 0x0f;
+        data[8 * KIB] = 0x00;
+        assert_eq!(flash.count_erases_for(start, &data)?, 0);
+        data[4 * KIB] = 0xf0;
+        data[12 * KIB] = 0x12;
+        assert_eq!(flash.count_erases_for(start, &data)?, 1);
+        assert_eq!(flash.count_erases_for(start, &[])?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn range_align_start_up() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |x: usize| flash.erasable_location(x as Location).unwrap();
+        let range = ErasableRange::new(location(4 * KIB), location(200 * KIB));
+        let aligned = range.align_start_up(block::Size::B64K)?;
+        assert_eq!(Location::from(aligned.beginning), 64 * KIB as Location);
+        assert_eq!(Location::from(aligned.end), 200 * KIB as Location);
+        let aligned = aligned.align_start_up(block::Size::B64K)?;
+        assert_eq!(Location::from(aligned.beginning), 64 * KIB as Location);
+        let range = ErasableRange::new(location(68 * KIB), location(128 * KIB));
+        assert_eq!(range.align_start_up(block::Size::B64K)?.capacity(), 0);
+        let range = ErasableRange::new(location(68 * KIB), location(124 * KIB));
+        assert!(matches!(
+            range.align_start_up(block::Size::B64K),
+            Err(Error::Size)
+        ));
+        let flash = FlashImage::with_geometry(&mut storage, 8 * KIB, &[]);
+        let range = ErasableRange::new(
+            flash.erasable_location(0).unwrap(),
+            flash.erasable_location(64 * KIB as Location).unwrap(),
+        );
+        assert!(matches!(
+            range.align_start_up(block::Size::B12K),
+            Err(Error::Alignment)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn block_uniform_byte() -> Result<()> {
+        let mut storage = [0xffu8; 12 * KIB];
+        storage[4 * KIB..8 * KIB].fill(0);
+        storage[8 * KIB + 3 * KIB] = 0;
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |x: usize| flash.erasable_location(x as Location).unwrap();
+        assert_eq!(flash.block_uniform_byte(location(0))?, Some(0xff));
+        assert_eq!(flash.block_uniform_byte(location(4 * KIB))?, Some(0));
+        assert_eq!(flash.block_uniform_byte(location(8 * KIB))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn location_sub() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location = flash.erasable_location(12 * KIB as Location).unwrap();
+        assert_eq!(Location::from(location.sub(8 * KIB)?), 4 * KIB as Location);
+        assert_eq!(Location::from(location.sub(12 * KIB)?), 0);
+        assert!(matches!(location.sub(16 * KIB), Err(Error::Overflow)));
+        assert!(matches!(location.sub(1), Err(Error::Alignment)));
+        assert_eq!(
+            Location::from(location.sub_round_up(1)?),
+            8 * KIB as Location
+        );
+        assert_eq!(
+            Location::from(location.sub_round_up(8 * KIB)?),
+            4 * KIB as Location
+        );
+        assert!(matches!(
+            location.sub_round_up(12 * KIB + 1),
+            Err(Error::Overflow)
+        ));
+        let zero = flash.erasable_location(0).unwrap();
+        assert_eq!(Location::from(zero.sub(0)?), 0);
+        assert!(matches!(zero.sub(4 * KIB), Err(Error::Overflow)));
+        assert!(matches!(zero.sub_round_up(usize::MAX), Err(Error::Overflow)));
+        Ok(())
+    }
+
+    #[test]
+    fn shared_flash() -> Result<()> {
+        use crate::chain::ChainFlash;
+        let mut storage = [0u8; 16 * KIB];
+        let mut a_storage = [0u8; 16 * KIB];
+        let mut b_storage = [0u8; 16 * KIB];
+        let shared = FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let a = FlashImage::with_geometry(&mut a_storage[..], 4 * KIB, &[]);
+        let b = FlashImage::with_geometry(&mut b_storage[..], 4 * KIB, &[]);
+        // SHARED is at [0, 16 KiB) of lower and at [16 KiB, 32 KiB) of
+        // upper.
+        let lower = ChainFlash::new(&shared, a)?;
+        let upper = ChainFlash::new(b, &shared)?;
+        let location = lower.erasable_location(0).unwrap();
+        lower.erase_and_write_block(location, &[1; 4 * KIB])?;
+        let location = upper.erasable_location(20 * KIB as Location).unwrap();
+        upper.erase_and_write_block(location, &[2; 4 * KIB])?;
+
+        let mut buf = [0u8; 8 * KIB];
+        lower.read_exact(0, &mut buf)?;
+        assert_eq!(buf[..4 * KIB], [1; 4 * KIB]);
+        assert_eq!(buf[4 * KIB..], [2; 4 * KIB]);
+        upper.read_exact(16 * KIB as Location, &mut buf)?;
+        assert_eq!(buf[..4 * KIB], [1; 4 * KIB]);
+        assert_eq!(buf[4 * KIB..], [2; 4 * KIB]);
+        drop((lower, upper));
+        shared.read_exact(0, &mut buf)?;
+        assert_eq!(buf[..4 * KIB], [1; 4 * KIB]);
+        assert_eq!(buf[4 * KIB..], [2; 4 * KIB]);
+        Ok(())
+    }
+
+    /// Counts the calls of its (overridden) erase_and_write_blocks.
+    struct BulkFlashImage<'a> {
+        image: FlashImage<'a>,
+        bulk_writes: core::cell::Cell<usize>,
+    }
+
+    impl FlashRead for BulkFlashImage<'_> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.image.read_exact(location, buffer)
+        }
+    }
+
+    impl FlashAlign for BulkFlashImage<'_> {
+        fn erasable_block_size(&self) -> usize {
+            self.image.erasable_block_size()
+        }
+    }
+
+    impl FlashWrite for BulkFlashImage<'_> {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.image.erase_block(location)
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.image.erase_and_write_block(location, buffer)
+        }
+        fn erase_and_write_blocks(
+            &self,
+            location: ErasableLocation,
+            buf: &[u8],
+        ) -> Result<()> {
+            self.bulk_writes.set(self.bulk_writes.get() + 1);
+            self.image.erase_and_write_blocks(location, buf)
+        }
+    }
+
+    #[test]
+    fn shared_flash_forwards_overrides() -> Result<()> {
+        fn write_at_0<F: FlashWrite>(flash: F, buf: &[u8]) -> Result<()> {
+            flash.erase_and_write_blocks(
+                flash.erasable_location(0).unwrap(),
+                buf,
+            )
+        }
+        let mut storage = [0u8; 16 * KIB];
+        let flash = BulkFlashImage {
+            image: FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]),
+            bulk_writes: core::cell::Cell::new(0),
+        };
+        write_at_0(&flash, &[1; 8 * KIB])?;
+        let dyn_flash: &dyn FlashWrite = &flash;
+        write_at_0(dyn_flash, &[2; 8 * KIB])?;
+        assert_eq!(flash.bulk_writes.get(), 2);
+        let mut buf = [0u8; 8 * KIB];
+        flash.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [2; 8 * KIB]);
+        Ok(())
+    }
+
+    /// The part of SOURCE between the braces after the first HEADER.
+    fn braced<'a>(source: &'a str, header: &str) -> &'a str {
+        let beginning = source.find(header).unwrap() + header.len();
+        let mut depth = 0;
+        for (i, c) in source[beginning..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 1 => return &source[beginning..beginning + i],
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        panic!("{} is not closed", header);
+    }
+
+    /// The methods directly in BODY (of a trait or an impl), each with
+    /// whether it has a default that an implementation can override (that
+    /// is, a body and no Self: Sized bound).
+    fn methods(body: &str) -> Vec<(&str, bool)> {
+        let mut result = Vec::new();
+        let mut depth = 0;
+        for (i, c) in body.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                'f' if depth == 1 && body[i..].starts_with("fn ") => {
+                    let rest = &body[i + 3..];
+                    let name_end = rest
+                        .find(|c: char| !c.is_alphanumeric() && c != '_')
+                        .unwrap();
+                    let signature_end = rest.find(['{', ';']).unwrap();
+                    let signature = &rest[..signature_end];
+                    let overridable = rest[signature_end..].starts_with('{')
+                        && !signature.contains("Sized");
+                    result.push((&rest[..name_end], overridable));
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn shared_flash_forwards_every_provided_method() {
+        // Without comments, so that their braces don't count.
+        let source: std::string::String = include_str!("lib.rs")
+            .lines()
+            .map(|line| line.split("//").next().unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        for name in ["FlashRead", "FlashAlign", "FlashWrite"] {
+            let methods_of_trait =
+                methods(braced(&source, &std::format!("pub trait {name}")));
+            let impl_header =
+                std::format!("impl<F: {name} + ?Sized> {name} for &F");
+            let forwarded = methods(braced(&source, &impl_header));
+            for (method, overridable) in methods_of_trait {
+                assert!(
+                    !overridable || forwarded.iter().any(|&(m, _)| m == method),
+                    "{}::{} is not forwarded by {}",
+                    name,
+                    method,
+                    impl_header
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn location_top_of_address_space() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let top = Location::MAX - Location::MAX % (4 * KIB as Location);
+        let zero = flash.erasable_location(0).unwrap();
+        let last = flash.erasable_location(top).unwrap();
+        assert_eq!(Location::from(zero.advance(top as usize)?), top);
+        assert_eq!(
+            Location::from(zero.advance_at_least(top as usize - 1)?),
+            top
+        );
+        assert_eq!(Location::from(last.advance(0)?), top);
+        assert_eq!(Location::from(last.advance_at_least(0)?), top);
+        assert_eq!(Location::from(last.prev_block()?.next_block()?), top);
+        let range = ErasableRange::try_new(last.prev_block()?, last)?;
+        assert_eq!(range.capacity(), 4 * KIB);
+
+        assert!(matches!(last.advance(4 * KIB), Err(Error::Overflow)));
+        assert!(matches!(last.advance_at_least(1), Err(Error::Overflow)));
+        assert!(matches!(last.next_block(), Err(Error::Overflow)));
+        assert!(matches!(
+            zero.advance(top as usize + 4 * KIB),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            zero.advance_at_least(top as usize + 1),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            zero.advance_at_least(usize::MAX),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            last.advance(usize::MAX & !0xfff),
+            Err(Error::Overflow)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn location_block_range() {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location = flash.erasable_location(8 * KIB as Location).unwrap();
+        assert_eq!(location.block_range(), 8 * KIB..12 * KIB);
+        let image = [0u8; 16 * KIB];
+        assert_eq!(image[location.block_range()].len(), 4 * KIB);
+    }
+
+    #[test]
+    fn block_location() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        assert_eq!(Location::from(flash.block_location(0)?), 0);
+        let location = flash.block_location(3)?;
+        assert_eq!(Location::from(location), 12 * KIB as Location);
+        assert_eq!(location.erasable_block_size(), 4 * KIB);
+        assert_eq!(
+            Location::from(flash.block_location(0xf_ffff)?),
+            0xffff_f000
+        );
+        assert!(matches!(
+            flash.block_location(0x10_0000),
+            Err(Error::Overflow)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn range_blocks() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let beginning = flash.erasable_location(8 * KIB as Location).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(16 * KIB)?);
+        let mut locations = [0; 4];
+        let mut count = 0;
+        for (i, location) in range.blocks().enumerate() {
+            assert_eq!(location.erasable_block_size(), 4 * KIB);
+            locations[i] = Location::from(location);
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        assert_eq!(locations, [8, 12, 16, 20].map(|x| x * KIB as Location));
+        assert!(locations
+            .windows(2)
+            .all(|w| w[1] - w[0] == 4 * KIB as Location));
+        assert_eq!(
+            ErasableRange::new(beginning, beginning).blocks().count(),
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn range_crosses_boundary() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let range = |beginning: usize, size: usize| {
+            let beginning =
+                flash.erasable_location(beginning as Location).unwrap();
+            ErasableRange::new(beginning, beginning.advance(size).unwrap())
+        };
+        let b64k = block::Size::B64K;
+        assert!(!range(0x1_0000, 0x1_0000).crosses_boundary(b64k));
+        assert!(!range(0x1_4000, 0x8000).crosses_boundary(b64k));
+        assert!(!range(0x1_8000, 0x8000).crosses_boundary(b64k));
+        assert!(range(0x1_c000, 0x8000).crosses_boundary(b64k));
+        assert!(range(0x1_f000, 0x2000).crosses_boundary(b64k));
+        assert!(range(0x1_8000, 0x1_0000).crosses_boundary(b64k));
+        // Needs two regions anyway
+        assert!(!range(0x1_8000, 0x1_8000).crosses_boundary(b64k));
+        assert!(range(0x1_8000, 0x2_0000).crosses_boundary(b64k));
+        assert!(!range(0x1_f000, 0).crosses_boundary(b64k));
+        assert!(!range(0x2000, 0x1000).crosses_boundary(block::Size::B12K));
+        assert!(range(0x2000, 0x2000).crosses_boundary(block::Size::B12K));
+        // Near the top of the address space
+        assert!(!range(0x1_0000, 0xfffe_0000).crosses_boundary(b64k));
+        assert!(range(0x8000, 0xfffe_0000).crosses_boundary(b64k));
+        assert!(!range(0xffff_0000, 0xf000).crosses_boundary(b64k));
+        Ok(())
+    }
+
+    #[test]
+    fn range_contains() -> Result<()> {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |x: usize| flash.erasable_location(x as Location).unwrap();
+        let range = ErasableRange::new(location(8 * KIB), location(16 * KIB));
+        assert!(!range.contains(8 * KIB as Location - 1));
+        assert!(range.contains(8 * KIB as Location));
+        assert!(range.contains(16 * KIB as Location - 1));
+        assert!(!range.contains(16 * KIB as Location));
+        assert!(!ErasableRange::new(location(0), location(0)).contains(0));
+
+        let inner = ErasableRange::new(location(12 * KIB), location(16 * KIB));
+        assert!(range.contains_range(&inner));
+        assert!(range.contains_range(&range));
+        assert!(!inner.contains_range(&range));
+        let straddling =
+            ErasableRange::new(location(12 * KIB), location(20 * KIB));
+        assert!(!range.contains_range(&straddling));
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn diff_ranges() -> Result<()> {
+        let mut storage = [0xffu8; 24 * KIB];
+        let mut reference = [0xffu8; 22 * KIB];
+        reference[4 * KIB] = 1;
+        reference[12 * KIB - 1] = 2;
+        reference[20 * KIB + 1] = 3;
+        storage[20 * KIB + 1] = 3;
+        storage[22 * KIB] = 4; // beyond REFERENCE
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let start = flash.erasable_location(0).unwrap();
+        let ranges = flash.diff_ranges(start, &reference)?;
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|range| (Location::from(range.beginning), range.capacity()))
+            .collect();
+        assert_eq!(ranges, [(4 * KIB as Location, 8 * KIB)]);
+
+        reference[16 * KIB] = 5;
+        reference[21 * KIB] = 6;
+        let ranges = flash.diff_ranges(start, &reference)?;
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|range| (Location::from(range.beginning), range.capacity()))
+            .collect();
+        assert_eq!(
+            ranges,
+            [(4 * KIB as Location, 8 * KIB), (16 * KIB as Location, 8 * KIB)]
+        );
+        assert!(flash.diff_ranges(start, &[0xff; 4 * KIB])?.is_empty());
+        assert!(flash.diff_ranges(start, &[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn range_intersect() {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |x: usize| flash.erasable_location(x as Location).unwrap();
+        let range =
+            |a: usize, b: usize| ErasableRange::new(location(a), location(b));
+        let a = range(4 * KIB, 16 * KIB);
+        let overlap = a.intersect(&range(12 * KIB, 20 * KIB)).unwrap();
+        assert_eq!(Location::from(overlap.beginning), 12 * KIB as Location);
+        assert_eq!(Location::from(overlap.end), 16 * KIB as Location);
+        let inner = a.intersect(&range(8 * KIB, 12 * KIB)).unwrap();
+        assert_eq!(Location::from(inner.beginning), 8 * KIB as Location);
+        assert_eq!(inner.capacity(), 4 * KIB);
+        assert!(a.intersect(&range(16 * KIB, 20 * KIB)).is_none());
+        assert!(a.intersect(&range(0, 4 * KIB)).is_none());
+        assert!(a.intersect(&range(8 * KIB, 8 * KIB)).is_none());
+    }
+
+    #[test]
+    fn range_merge() {
+        let mut storage = [0xffu8; 0];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |x: usize| flash.erasable_location(x as Location).unwrap();
+        let range =
+            |a: usize, b: usize| ErasableRange::new(location(a), location(b));
+        let bounds = |range: ErasableRange| {
+            (Location::from(range.beginning), Location::from(range.end))
+        };
+        let a = range(4 * KIB, 8 * KIB);
+        let b = range(8 * KIB, 16 * KIB);
+        let expected = (4 * KIB as Location, 16 * KIB as Location);
+        assert_eq!(bounds(a.merge(&b).unwrap()), expected);
+        assert_eq!(bounds(b.merge(&a).unwrap()), expected);
+        let c = range(0, 12 * KIB);
+        assert_eq!(bounds(b.merge(&c).unwrap()), (0, 16 * KIB as Location));
+        assert_eq!(bounds(c.merge(&a).unwrap()), (0, 12 * KIB as Location));
+        let d = range(20 * KIB, 24 * KIB);
+        assert!(b.merge(&d).is_none());
+        assert!(d.merge(&b).is_none());
+    }
+
+    #[test]
+    fn erase_and_write_blocks_partial_tail() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let mut data = [0u8; 10 * KIB + 1];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        flash.erase_and_write_blocks(beginning, &data)?;
+        let mut buf = [0u8; 16 * KIB];
+        flash.read_exact(0, &mut buf)?;
+        assert!(buf[..4 * KIB].iter().all(|&x| x == 0));
+        assert_eq!(buf[4 * KIB..14 * KIB + 1], data);
+        assert!(buf[14 * KIB + 1..].iter().all(|&x| x == 0xff));
+        Ok(())
+    }
+
+    #[test]
+    fn read_tail() -> Result<()> {
+        let mut storage = [0u8; 32 * KIB];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = (i / (4 * KIB)) as u8;
+        }
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let beginning = flash.erasable_location(8 * KIB as Location).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(20 * KIB)?);
+        let mut buf = [0xffu8; 8 * KIB];
+        flash.read_tail(&range, 2, &mut buf)?;
+        assert!(buf[..4 * KIB].iter().all(|&x| x == 5));
+        assert!(buf[4 * KIB..].iter().all(|&x| x == 6));
+        flash.read_tail(&range, 0, &mut [])?;
+
+        let mut buf = [0u8; 24 * KIB];
+        flash.read_tail(&range, 5, &mut buf[..20 * KIB])?;
+        assert_eq!(buf[0], 2);
+        assert!(matches!(
+            flash.read_tail(&range, 6, &mut buf),
+            Err(Error::Size)
+        ));
+        assert!(matches!(
+            flash.read_tail(&range, 2, &mut buf[..8 * KIB - 1]),
+            Err(Error::Size)
+        ));
+        assert!(matches!(
+            flash.read_tail(&range, usize::MAX, &mut buf),
+            Err(Error::Size)
+        ));
+        Ok(())
+    }
+
+    /// Counts the erases, including those of erase_and_write_block.
+    struct CountingErases<'a> {
+        flash: FlashImage<'a>,
+        erases: Cell<usize>,
+    }
+    impl FlashRead for CountingErases<'_> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.flash.read_exact(location, buffer)
+        }
+    }
+    impl FlashAlign for CountingErases<'_> {
+        fn erasable_block_size(&self) -> usize {
+            self.flash.erasable_block_size()
+        }
+    }
+    impl FlashWrite for CountingErases<'_> {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.erases.set(self.erases.get() + 1);
+            self.flash.erase_block(location)
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.erases.set(self.erases.get() + 1);
+            self.flash.erase_and_write_block(location, buffer)
+        }
+        fn write_erased_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.flash.write_erased_block(location, buffer)
+        }
+    }
+
+    #[test]
+    fn write_block_opt() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        storage[4 * KIB..4 * KIB + 4].copy_from_slice(&[1, 2, 3, 4]);
+        storage[8 * KIB] = 0;
+        let flash = CountingErases {
+            flash: FlashImage::with_geometry(&mut storage, 4 * KIB, &[]),
+            erases: Cell::new(0),
+        };
+        let location =
+            |a: usize| flash.erasable_location(a as Location).unwrap();
+        // Already the same
+        flash.write_block_opt(location(4 * KIB), &[1, 2, 3, 4])?;
+        flash.write_block_opt(location(12 * KIB), &[])?;
+        // Erased
+        flash.write_block_opt(location(0), &[5, 6])?;
+        // Neither
+        flash.write_block_opt(location(8 * KIB), &[7; 4 * KIB])?;
+        flash.write_block_opt(location(4 * KIB), &[1, 2, 3])?;
+        assert!(matches!(
+            flash.write_block_opt(location(0), &[0; 4 * KIB + 1]),
+            Err(Error::Programmer)
+        ));
+        assert_eq!(flash.erases.get(), 2);
+        let mut buf = [0u8; 4];
+        flash.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [5, 6, 0xff, 0xff]);
+        flash.read_exact(4 * KIB as Location, &mut buf)?;
+        assert_eq!(buf, [1, 2, 3, 0xff]);
+        flash.read_exact(12 * KIB as Location - 2, &mut buf)?;
+        assert_eq!(buf, [7, 7, 0xff, 0xff]);
+        Ok(())
+    }
+
+    #[test]
+    fn patch() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        let original = storage;
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location = flash.erasable_location(4 * KIB as Location).unwrap();
+        let mut scratch = [0u8; 4 * KIB];
+        flash.patch(location, 0x800, &[1, 2, 3, 4], &mut scratch)?;
+        flash.patch(location, 4 * KIB - 1, &[5], &mut scratch)?;
+        assert!(matches!(
+            flash.patch(location, 4 * KIB - 1, &[6, 7], &mut scratch),
+            Err(Error::Overflow)
+        ));
+        assert!(matches!(
+            flash.patch(location, 0, &[], &mut scratch[..KIB]),
+            Err(Error::Programmer)
+        ));
+        drop(flash);
+        let patched = 4 * KIB + 0x800..4 * KIB + 0x804;
+        assert_eq!(storage[patched.clone()], [1, 2, 3, 4]);
+        assert_eq!(storage[8 * KIB - 1], 5);
+        assert_eq!(storage[..patched.start], original[..patched.start]);
+        assert_eq!(
+            storage[patched.end..8 * KIB - 1],
+            original[patched.end..8 * KIB - 1]
+        );
+        assert_eq!(storage[8 * KIB..], original[8 * KIB..]);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_range() -> Result<()> {
+        let mut storage = [0xffu8; 32 * KIB];
+        for (i, x) in storage[4 * KIB..12 * KIB].iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        storage[24 * KIB..28 * KIB].fill(0);
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let range = |a: usize, size: usize| {
+            let beginning = flash.erasable_location(a as Location).unwrap();
+            ErasableRange::new(beginning, beginning.advance(size).unwrap())
+        };
+        let mut scratch = [0u8; 4 * KIB];
+        let dst = flash.erasable_location(16 * KIB as Location).unwrap();
+        flash.copy_range(&range(4 * KIB, 8 * KIB), dst, &mut scratch)?;
+        let mut a = [0u8; 8 * KIB];
+        let mut b = [0u8; 12 * KIB];
+        flash.read_exact(4 * KIB as Location, &mut a)?;
+        flash.read_exact(16 * KIB as Location, &mut b)?;
+        assert_eq!(a, b[..8 * KIB]);
+        assert!(b[8 * KIB..].iter().all(|&x| x == 0));
+        drop(flash);
+        assert_eq!(storage[4 * KIB..12 * KIB], storage[16 * KIB..24 * KIB]);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_range_overlap() -> Result<()> {
+        let mut storage = [0xffu8; 32 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let range = |a: usize, size: usize| {
+            let beginning = flash.erasable_location(a as Location).unwrap();
+            ErasableRange::new(beginning, beginning.advance(size).unwrap())
+        };
+        let location =
+            |a: usize| flash.erasable_location(a as Location).unwrap();
+        let mut scratch = [0u8; 4 * KIB];
+        let src = range(8 * KIB, 8 * KIB);
+        for dst in [4 * KIB, 8 * KIB, 12 * KIB] {
+            assert!(matches!(
+                flash.copy_range(&src, location(dst), &mut scratch),
+                Err(Error::Programmer)
+            ));
+        }
+        flash.copy_range(&src, location(16 * KIB), &mut scratch)?;
+        flash.copy_range(&src, location(0), &mut scratch)?;
+        assert!(matches!(
+            flash.copy_range(&src, location(0), &mut scratch[..KIB]),
+            Err(Error::Programmer)
+        ));
+        assert!(flash.erases.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn is_blank() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location =
+            |a: usize| flash.erasable_location(a as Location).unwrap();
+        flash.erase_block(location(4 * KIB))?;
+        flash.erase_and_write_block(location(8 * KIB), &[0xff; 4 * KIB - 1])?;
+        flash.erase_and_write_block(location(12 * KIB), &[0xff, 0xff, 0])?;
+        assert!(!flash.is_blank(location(0))?);
+        assert!(flash.is_blank(location(4 * KIB))?);
+        assert!(flash.is_blank(location(8 * KIB))?);
+        assert!(!flash.is_blank(location(12 * KIB))?);
+        let range =
+            |a: usize, b: usize| ErasableRange::new(location(a), location(b));
+        assert!(flash.is_range_blank(&range(4 * KIB, 12 * KIB))?);
+        assert!(!flash.is_range_blank(&range(4 * KIB, 16 * KIB))?);
+        assert!(!flash.is_range_blank(&range(0, 8 * KIB))?);
+        assert!(flash.is_range_blank(&range(0, 0))?);
+        Ok(())
+    }
+
+    #[test]
+    fn crc32() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        for (i, x) in storage[4 * KIB..12 * KIB].iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let range = |a: usize, size: usize| {
+            let beginning = flash.erasable_location(a as Location).unwrap();
+            ErasableRange::new(beginning, beginning.advance(size).unwrap())
+        };
+        assert_eq!(flash.crc32(&range(4 * KIB, 8 * KIB))?, 0xfe7c_712f);
+        assert_eq!(flash.crc32(&range(12 * KIB, 4 * KIB))?, 0xf154_670a);
+        assert_eq!(flash.crc32(&range(0, 0))?, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn sha256() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        for (i, x) in storage[4 * KIB..12 * KIB].iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(8 * KIB)?);
+        let digest = [
+            0x25, 0xdf, 0x24, 0x49, 0xb2, 0xe5, 0xa3, 0x5f, 0xea, 0x14, 0xe0,
+            0x2a, 0x71, 0x58, 0xe2, 0x83, 0x80, 0x1a, 0x10, 0x69, 0xc9, 0xf8,
+            0x46, 0x31, 0xb9, 0xa9, 0xda, 0xcb, 0x2f, 0x80, 0x9a, 0x7f,
+        ];
+        assert_eq!(flash.sha256(&range)?, digest);
+
+        let flash = crate::mem::MemFlash::blank(8 * KIB, 4 * KIB)?;
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(8 * KIB)?);
+        assert!(matches!(flash.sha256(&range), Err(Error::Io { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn region_hash() -> Result<()> {
+        let mut storage = [0xffu8; 16 * KIB];
+        storage[..4 * KIB].fill(0x42);
+        storage[8 * KIB..12 * KIB].fill(0x42);
+        storage[15 * KIB] = 0;
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let range = |a: usize| {
+            let beginning = flash.erasable_location(a as Location).unwrap();
+            ErasableRange::new(beginning, beginning.advance(4 * KIB).unwrap())
+        };
+        let a = flash.region_hash(&range(0))?;
+        assert_eq!(a, flash.region_hash(&range(8 * KIB))?);
+        assert_ne!(a, flash.region_hash(&range(4 * KIB))?);
+        assert_ne!(
+            flash.region_hash(&range(4 * KIB))?,
+            flash.region_hash(&range(12 * KIB))?
+        );
+        let mut expected = checksum::Fnv1a64::new();
+        expected.update(&[0x42; 4 * KIB]);
+        assert_eq!(a, expected.finalize());
+        Ok(())
+    }
+
+    /// Corrupts the byte at CORRUPT whenever it is written
+    struct CorruptingFlashImage<'a> {
+        flash: FlashImage<'a>,
+        corrupt: Location,
+    }
+
+    impl FlashRead for CorruptingFlashImage<'_> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.flash.read_exact(location, buffer)
+        }
+    }
+
+    impl FlashAlign for CorruptingFlashImage<'_> {
+        fn erasable_block_size(&self) -> usize {
+            self.flash.erasable_block_size()
+        }
+    }
+
+    impl FlashWrite for CorruptingFlashImage<'_> {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.flash.erase_block(location)
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.flash.erase_and_write_block(location, buffer)?;
+            let offset = self.corrupt.wrapping_sub(location.into()) as usize;
+            if offset < buffer.len() {
+                let mut buf = self.flash.buf.borrow_mut();
+                buf[self.corrupt as usize] ^= 0x04;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_checked() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let location = flash.erasable_location(8 * KIB as Location).unwrap();
+        let data = [0x5au8; 8 * KIB + 1];
+        assert!(matches!(
+            flash.write_checked(location, &data),
+            Err(Error::OutOfBounds { location }) if location == 8 * KIB as Location
+        ));
+        assert!(flash.erases.borrow().is_empty());
+        let mut buf = [0xffu8; 16 * KIB];
+        flash.read_exact(0, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0));
+
+        flash.write_checked(location, &data[..8 * KIB])?;
+        flash.read_exact(0, &mut buf)?;
+        assert!(buf[..8 * KIB].iter().all(|&x| x == 0));
+        assert!(buf[8 * KIB..].iter().all(|&x| x == 0x5a));
+        let last = flash.erasable_location(0xffff_f000).unwrap();
+        assert!(matches!(
+            flash.write_checked(last, &data),
+            Err(Error::Overflow)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn write_verified() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash = CorruptingFlashImage {
+            flash: FlashImage::with_geometry(&mut storage, 4 * KIB, &[]),
+            corrupt: 9 * KIB as Location,
+        };
+        let beginning = flash.erasable_location(0).unwrap();
+        let data = [0x5au8; 9 * KIB + 1];
+        flash.write_verified(beginning, &data[..8 * KIB])?;
+        flash.write_verified(beginning, &data[..9 * KIB])?;
+        assert!(matches!(
+            flash.write_verified(beginning, &data),
+            Err(Error::Io { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn erase_range_two_blocks() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        flash.erase_range(&ErasableRange::new(
+            beginning,
+            beginning.advance(8 * KIB)?,
+        ))?;
+        let mut buf = [0u8; 16 * KIB];
+        flash.read_exact(0, &mut buf)?;
+        assert!(buf[..4 * KIB].iter().all(|&x| x == 0));
+        assert!(buf[4 * KIB..12 * KIB].iter().all(|&x| x == 0xff));
+        assert!(buf[12 * KIB..].iter().all(|&x| x == 0));
+        assert_eq!(*flash.erases.borrow(), [4 * KIB, 4 * KIB]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_exact_uninit() -> Result<()> {
+        let mut storage = [0u8; 4 * KIB];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let flash = FlashImage::with_geometry(&mut storage, 4 * KIB, &[]);
+        let mut buf = [core::mem::MaybeUninit::<u8>::uninit(); 300];
+        let data = flash.read_exact_uninit(0x10, &mut buf)?;
+        assert_eq!(data.len(), 300);
+        assert!(data.iter().enumerate().all(|(i, &x)| x == (i + 0x10) as u8));
+        Ok(())
+    }
 }
@@ -0,0 +1,386 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    ErasableLocation, ErasableRange, FlashAlign, FlashRead, FlashWrite,
+    Location,
+};
+use crate::{Error, Result};
+use core::cell::{Ref, RefCell};
+use core::mem::MaybeUninit;
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+/// Flash chip simulated in memory (for example for tests and host tools).
+/// S is the backing storage, for example a `&mut [u8]`.
+pub struct MemFlash<S> {
+    buf: RefCell<S>,
+    erasable_block_size: usize,
+    #[cfg(feature = "std")]
+    snapshot: RefCell<SnapshotState>,
+}
+
+/// Handle for restoring the contents of a MemFlash to the state at the
+/// time it was taken (see MemFlash::snapshot).
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Snapshot {
+    id: usize,
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct SnapshotState {
+    /// Id of the most recent snapshot
+    id: usize,
+    /// Original contents of each block changed since the most recent
+    /// snapshot, by block index; None if there is no active snapshot
+    blocks: Option<BTreeMap<usize, Vec<u8>>>,
+}
+
+impl<S: AsRef<[u8]> + AsMut<[u8]>> MemFlash<S> {
+    /// Note: ERASABLE_BLOCK_SIZE has to be a power of two, and the size of
+    /// BUF has to be a multiple of it.
+    pub fn new(buf: S, erasable_block_size: usize) -> Result<Self> {
+        if !erasable_block_size.is_power_of_two()
+            || buf.as_ref().len() % erasable_block_size != 0
+        {
+            return Err(Error::Alignment);
+        }
+        Ok(Self {
+            buf: RefCell::new(buf),
+            erasable_block_size,
+            #[cfg(feature = "std")]
+            snapshot: RefCell::new(SnapshotState::default()),
+        })
+    }
+    pub fn into_inner(self) -> S {
+        self.buf.into_inner()
+    }
+    /// Returns the backing bytes of RANGE.
+    /// Note: Panics if RANGE is out of bounds, like indexing does.
+    pub fn slice(&self, range: &ErasableRange) -> Ref<'_, [u8]> {
+        let beginning = Location::from(range.beginning) as usize;
+        let end = Location::from(range.end) as usize;
+        Ref::map(self.buf.borrow(), |buf| &buf.as_ref()[beginning..end])
+    }
+    /// Iterates over the erasable blocks in RANGE, giving direct mutable
+    /// access to the backing bytes of each (for example to set up test
+    /// fixtures).
+    /// Note: Panics if RANGE is out of bounds, like indexing does.
+    pub fn blocks_mut(
+        &mut self,
+        range: &ErasableRange,
+    ) -> impl Iterator<Item = (ErasableLocation, &mut [u8])> {
+        let erasable_block_size = self.erasable_block_size;
+        let beginning = Location::from(range.beginning);
+        let end = Location::from(range.end);
+        self.save(beginning as usize, end as usize);
+        self.buf.get_mut().as_mut()[beginning as usize..end as usize]
+            .chunks_mut(erasable_block_size)
+            .enumerate()
+            .map(move |(i, block)| {
+                let location = ErasableLocation {
+                    location: beginning + (i * erasable_block_size) as Location,
+                    erasable_block_size,
+                };
+                (location, block)
+            })
+    }
+    /// Returns the range of indices into the backing storage that
+    /// corresponds to [BEGINNING, BEGINNING + SIZE), if that is in bounds.
+    fn span(&self, beginning: Location, size: usize) -> Result<(usize, usize)> {
+        let beginning_index = beginning as usize;
+        let end_index = beginning_index
+            .checked_add(size)
+            .filter(|&end| end <= self.buf.borrow().as_ref().len())
+            .ok_or(Error::OutOfBounds { location: beginning })?;
+        Ok((beginning_index, end_index))
+    }
+    /// Remembers the original contents of the blocks in
+    /// [BEGINNING, END) for the active snapshot, if any, before they are
+    /// changed.
+    #[cfg(feature = "std")]
+    fn save(&self, beginning: usize, end: usize) {
+        let erasable_block_size = self.erasable_block_size;
+        let buf = self.buf.borrow();
+        if let Some(blocks) = &mut self.snapshot.borrow_mut().blocks {
+            for index in beginning / erasable_block_size
+                ..(end + erasable_block_size - 1) / erasable_block_size
+            {
+                blocks.entry(index).or_insert_with(|| {
+                    let beginning = index * erasable_block_size;
+                    buf.as_ref()[beginning..beginning + erasable_block_size]
+                        .to_vec()
+                });
+            }
+        }
+    }
+    #[cfg(not(feature = "std"))]
+    fn save(&self, _beginning: usize, _end: usize) {}
+}
+
+#[cfg(feature = "std")]
+impl<S: AsRef<[u8]> + AsMut<[u8]>> MemFlash<S> {
+    /// Takes a snapshot of the current contents, that restore can go back
+    /// to.
+    /// This doesn't copy anything yet; instead, each block is copied the
+    /// first time it is changed afterwards.
+    /// Note: Only the most recent snapshot can be restored.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut state = self.snapshot.borrow_mut();
+        state.id += 1;
+        state.blocks = Some(BTreeMap::new());
+        Snapshot { id: state.id }
+    }
+    /// Restores the contents at the time SNAPSHOT was taken.
+    /// Returns Error::Programmer if SNAPSHOT is not the most recent
+    /// snapshot.
+    pub fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        let mut state = self.snapshot.borrow_mut();
+        if snapshot.id != state.id {
+            return Err(Error::Programmer);
+        }
+        let blocks = state.blocks.take().ok_or(Error::Programmer)?;
+        let mut buf = self.buf.borrow_mut();
+        for (index, block) in blocks {
+            let beginning = index * self.erasable_block_size;
+            buf.as_mut()[beginning..beginning + block.len()]
+                .copy_from_slice(&block);
+        }
+        Ok(())
+    }
+    /// Number of blocks copied for the active snapshot so far.
+    pub fn snapshot_copied_blocks(&self) -> usize {
+        self.snapshot.borrow().blocks.as_ref().map_or(0, |blocks| blocks.len())
+    }
+}
+
+#[cfg(feature = "std")]
+impl MemFlash<Vec<u8>> {
+    /// Creates a flash chip of CAPACITY Byte that is entirely erased
+    /// (0xff).
+    /// Note: CAPACITY has to be a multiple of ERASABLE_BLOCK_SIZE.
+    pub fn blank(capacity: usize, erasable_block_size: usize) -> Result<Self> {
+        if !erasable_block_size.is_power_of_two()
+            || capacity % erasable_block_size != 0
+        {
+            return Err(Error::Alignment);
+        }
+        Self::new(vec![0xff; capacity], erasable_block_size)
+    }
+}
+
+impl<S: AsRef<[u8]> + AsMut<[u8]>> FlashRead for MemFlash<S> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        let (beginning, end) = self.span(beginning, buffer.len())?;
+        buffer.copy_from_slice(&self.buf.borrow().as_ref()[beginning..end]);
+        Ok(())
+    }
+    fn capacity(&self) -> Option<usize> {
+        Some(self.buf.borrow().as_ref().len())
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        let (beginning, end) = self.span(beginning, buffer.len())?;
+        let buf = self.buf.borrow();
+        for (x, &y) in buffer.iter_mut().zip(&buf.as_ref()[beginning..end]) {
+            x.write(y);
+        }
+        // SAFETY: All of BUFFER was just initialized, since it has the
+        // same length as the span.
+        Ok(unsafe { crate::assume_init(buffer) })
+    }
+}
+
+impl<S> FlashAlign for MemFlash<S> {
+    fn erasable_block_size(&self) -> usize {
+        self.erasable_block_size
+    }
+}
+
+impl<S: AsRef<[u8]> + AsMut<[u8]>> FlashWrite for MemFlash<S> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let (beginning, end) =
+            self.span(self.location(location)?, self.erasable_block_size)?;
+        self.save(beginning, end);
+        self.buf.borrow_mut().as_mut()[beginning..end].fill(0xff);
+        Ok(())
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let (beginning, end) =
+            self.span(self.location(location)?, self.erasable_block_size)?;
+        self.save(beginning, end);
+        let mut buf = self.buf.borrow_mut();
+        let (data, remainder) =
+            buf.as_mut()[beginning..end].split_at_mut(buffer.len());
+        data.copy_from_slice(buffer);
+        remainder.fill(0xff);
+        Ok(())
+    }
+    /// Like NOR flash, writing can only clear bits, so BUFFER is ANDed in.
+    fn write_erased_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let (beginning, _) =
+            self.span(self.location(location)?, self.erasable_block_size)?;
+        let end = beginning + buffer.len();
+        self.save(beginning, end);
+        let mut buf = self.buf.borrow_mut();
+        for (x, &y) in buf.as_mut()[beginning..end].iter_mut().zip(buffer) {
+            *x &= y;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod mem_tests {
+    use super::*;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn mem_flash_slice() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let beginning = mem.erasable_location(4 * KIB as u32).unwrap();
+        mem.erase_and_write_block(beginning, &[1, 2, 3])?;
+        let range = ErasableRange::new(beginning, beginning.advance(4 * KIB)?);
+        let mut expected = [0xffu8; 4 * KIB];
+        expected[..3].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(&*mem.slice(&range), &expected[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn mem_flash_blocks_mut() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let mut mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let beginning = mem.erasable_location(4 * KIB as u32).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(8 * KIB)?);
+        let mut locations = [0; 2];
+        for (i, (location, block)) in mem.blocks_mut(&range).enumerate() {
+            locations[i] = Location::from(location);
+            block.fill(i as u8 + 1);
+        }
+        assert_eq!(locations, [4 * KIB as u32, 8 * KIB as u32]);
+        let mut buf = [0u8; 4 * KIB];
+        mem.read_erasable_block(beginning, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 1));
+        mem.read_erasable_block(beginning.advance(4 * KIB)?, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 2));
+        mem.read_erasable_block(beginning.advance(8 * KIB)?, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn mem_flash_write_erased_block() -> Result<()> {
+        let mut storage = [0u8; 8 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let location = mem.erasable_location(4 * KIB as u32).unwrap();
+        mem.erase_and_write_block(location, &[1; 4])?;
+        // Only clears bits, and leaves the remainder alone.
+        mem.write_erased_block(location, &[2, 3])?;
+        let mut buf = [0u8; 6];
+        mem.read_exact(4 * KIB as u32, &mut buf)?;
+        assert_eq!(buf, [0, 1, 1, 1, 0xff, 0xff]);
+        assert!(matches!(
+            mem.write_erased_block(location, &[0; 4 * KIB + 1]),
+            Err(Error::Programmer)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn mem_flash_out_of_bounds() -> Result<()> {
+        let mut storage = [0u8; 8 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let mut buf = [0u8; 2];
+        assert!(matches!(
+            mem.read_exact(8 * KIB as u32 - 1, &mut buf),
+            Err(Error::OutOfBounds { .. })
+        ));
+        let location = mem.erasable_location(8 * KIB as u32).unwrap();
+        assert!(matches!(
+            mem.erase_block(location),
+            Err(Error::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            MemFlash::new(&mut storage[..6 * KIB], 4 * KIB),
+            Err(Error::Alignment)
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn mem_flash_blank() -> Result<()> {
+        let mem = MemFlash::blank(16 * KIB, 4 * KIB)?;
+        assert_eq!(mem.capacity(), Some(16 * KIB));
+        let beginning = mem.erasable_location(0).unwrap();
+        let range = ErasableRange::new(beginning, beginning.advance(16 * KIB)?);
+        assert!(mem.slice(&range).iter().all(|&x| x == 0xff));
+        assert!(matches!(
+            MemFlash::blank(6 * KIB, 4 * KIB),
+            Err(Error::Alignment)
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn mem_flash_snapshot() -> Result<()> {
+        let mem = MemFlash::blank(64 * KIB, 4 * KIB)?;
+        let beginning = mem.erasable_location(8 * KIB as u32).unwrap();
+        mem.erase_and_write_block(beginning, &[1, 2, 3])?;
+        let snapshot = mem.snapshot();
+        assert_eq!(mem.snapshot_copied_blocks(), 0);
+        mem.erase_and_write_block(beginning, &[4, 5])?;
+        mem.erase_block(beginning)?;
+        assert_eq!(mem.snapshot_copied_blocks(), 1);
+        mem.restore(snapshot)?;
+        let mut buf = [0u8; 4];
+        mem.read_exact(8 * KIB as u32, &mut buf)?;
+        assert_eq!(buf, [1, 2, 3, 0xff]);
+        assert_eq!(mem.snapshot_copied_blocks(), 0);
+
+        let old = mem.snapshot();
+        let new = mem.snapshot();
+        assert!(matches!(mem.restore(old), Err(Error::Programmer)));
+        mem.restore(new)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mem_flash_read_exact_uninit() -> Result<()> {
+        let mut storage = [0u8; 8 * KIB];
+        storage[4 * KIB..].fill(0x42);
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let mut buf = [MaybeUninit::<u8>::uninit(); 2 * KIB];
+        let data = mem.read_exact_uninit(3 * KIB as u32, &mut buf)?;
+        assert!(data[..KIB].iter().all(|&x| x == 0));
+        assert!(data[KIB..].iter().all(|&x| x == 0x42));
+        assert!(matches!(
+            mem.read_exact_uninit(7 * KIB as u32, &mut buf),
+            Err(Error::OutOfBounds { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableRange, FlashWrite, Location};
+use crate::{Error, Result};
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::Alignment => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds { .. } => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Makes the flash chip F available via the embedded-storage traits.
+/// BSIZE is the erasable block size of F; since NorFlash cannot change
+/// part of an erasable block without erasing it, it is both the erase and
+/// the write granularity.
+pub struct NorFlashAdapter<F, const BSIZE: usize> {
+    flash: F,
+    capacity: usize,
+}
+
+impl<F: FlashWrite, const BSIZE: usize> NorFlashAdapter<F, BSIZE> {
+    /// FLASH has to report its capacity (otherwise returns
+    /// Error::Programmer), and its erasable block size has to be BSIZE
+    /// (otherwise returns Error::Alignment).
+    pub fn new(flash: F) -> Result<Self> {
+        let capacity = flash.capacity().ok_or(Error::Programmer)?;
+        if flash.erasable_block_size() != BSIZE {
+            return Err(Error::Alignment);
+        }
+        Ok(Self { flash, capacity })
+    }
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+    /// Checks that [OFFSET, OFFSET + SIZE) is within the flash chip.
+    fn check_bounds(&self, offset: u32, size: usize) -> Result<()> {
+        match (offset as usize).checked_add(size) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(Error::OutOfBounds { location: offset }),
+        }
+    }
+}
+
+impl<F, const BSIZE: usize> ErrorType for NorFlashAdapter<F, BSIZE> {
+    type Error = Error;
+}
+
+impl<F: FlashWrite, const BSIZE: usize> ReadNorFlash
+    for NorFlashAdapter<F, BSIZE>
+{
+    const READ_SIZE: usize = 1;
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<()> {
+        self.check_bounds(offset, bytes.len())?;
+        self.flash.read_exact(offset as Location, bytes)
+    }
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<F: FlashWrite, const BSIZE: usize> NorFlash for NorFlashAdapter<F, BSIZE> {
+    const WRITE_SIZE: usize = BSIZE;
+    const ERASE_SIZE: usize = BSIZE;
+    fn erase(&mut self, from: u32, to: u32) -> Result<()> {
+        let size = to.checked_sub(from).ok_or(Error::Programmer)?;
+        self.check_bounds(from, size as usize)?;
+        let beginning = self
+            .flash
+            .erasable_location(from as Location)
+            .ok_or(Error::Alignment)?;
+        let end = self
+            .flash
+            .erasable_location(to as Location)
+            .ok_or(Error::Alignment)?;
+        self.flash.erase_range(&ErasableRange::try_new(beginning, end)?)
+    }
+    /// Note: This erases the blocks before writing them, so it does not
+    /// matter whether they were erased before.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<()> {
+        self.check_bounds(offset, bytes.len())?;
+        let beginning = self
+            .flash
+            .erasable_location(offset as Location)
+            .ok_or(Error::Alignment)?;
+        if bytes.len() % BSIZE != 0 {
+            return Err(Error::Alignment);
+        }
+        self.flash.erase_and_write_blocks(beginning, bytes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod nor_tests {
+    use super::*;
+    use crate::mem::MemFlash;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn nor_flash_cycle() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        let mut flash = NorFlashAdapter::<_, { 4 * KIB }>::new(mem)?;
+        assert_eq!(flash.capacity(), 16 * KIB);
+        let mut buf = [0xffu8; 6 * KIB];
+        flash.read(2 * KIB as u32, &mut buf)?;
+        assert!(buf.iter().all(|&x| x == 0));
+
+        flash.erase(4 * KIB as u32, 12 * KIB as u32)?;
+        flash.read(2 * KIB as u32, &mut buf)?;
+        assert!(buf[..2 * KIB].iter().all(|&x| x == 0));
+        assert!(buf[2 * KIB..].iter().all(|&x| x == 0xff));
+
+        let data = [0x5au8; 8 * KIB];
+        flash.write(4 * KIB as u32, &data)?;
+        let mut buf = [0u8; 10 * KIB];
+        flash.read(3 * KIB as u32, &mut buf)?;
+        assert!(buf[..KIB].iter().all(|&x| x == 0));
+        assert_eq!(buf[KIB..9 * KIB], data);
+        assert!(buf[9 * KIB..].iter().all(|&x| x == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn nor_flash_errors() -> Result<()> {
+        let mut storage = [0u8; 16 * KIB];
+        let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+        assert!(matches!(
+            NorFlashAdapter::<_, { 8 * KIB }>::new(&mem),
+            Err(Error::Alignment)
+        ));
+        let mut flash = NorFlashAdapter::<_, { 4 * KIB }>::new(&mem)?;
+        let kind = |result: Result<()>| result.unwrap_err().kind();
+        assert_eq!(
+            kind(flash.erase(KIB as u32, 4 * KIB as u32)),
+            NorFlashErrorKind::NotAligned
+        );
+        assert_eq!(
+            kind(flash.write(0, &[0u8; KIB])),
+            NorFlashErrorKind::NotAligned
+        );
+        assert_eq!(
+            kind(flash.erase(12 * KIB as u32, 20 * KIB as u32)),
+            NorFlashErrorKind::OutOfBounds
+        );
+        assert_eq!(
+            kind(flash.write(16 * KIB as u32, &[0u8; 4 * KIB])),
+            NorFlashErrorKind::OutOfBounds
+        );
+        let mut buf = [0u8; 2];
+        assert_eq!(
+            kind(flash.read(16 * KIB as u32 - 1, &mut buf)),
+            NorFlashErrorKind::OutOfBounds
+        );
+        assert_eq!(
+            kind(flash.erase(8 * KIB as u32, 4 * KIB as u32)),
+            NorFlashErrorKind::Other
+        );
+        Ok(())
+    }
+}
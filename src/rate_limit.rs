@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::Result;
+use crate::{ErasableLocation, FlashAlign, FlashRead, FlashWrite, Location};
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+
+/// Passes everything through to the flash chip D, but calls DELAY before
+/// each erase (including the erase in erase_and_write_block), for flash
+/// chips that fail when erases are issued too quickly.
+/// DELAY is responsible for the pacing (for example by sleeping or by
+/// waiting on a timer), so that this does not need a clock.
+pub struct RateLimited<'a, D> {
+    flash: D,
+    delay: RefCell<&'a mut dyn FnMut()>,
+}
+
+impl<'a, D> RateLimited<'a, D> {
+    pub fn new(flash: D, delay: &'a mut dyn FnMut()) -> Self {
+        Self { flash, delay: RefCell::new(delay) }
+    }
+    pub fn into_inner(self) -> D {
+        self.flash
+    }
+    fn delay(&self) {
+        (self.delay.borrow_mut())();
+    }
+}
+
+impl<D: FlashRead> FlashRead for RateLimited<'_, D> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        self.flash.read_exact(beginning, buffer)
+    }
+    fn capacity(&self) -> Option<usize> {
+        self.flash.capacity()
+    }
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        self.flash.read_exact_uninit(beginning, buffer)
+    }
+}
+
+impl<D: FlashAlign> FlashAlign for RateLimited<'_, D> {
+    fn erasable_block_size(&self) -> usize {
+        self.flash.erasable_block_size()
+    }
+    fn preferred_read_chunk(&self) -> usize {
+        self.flash.preferred_read_chunk()
+    }
+}
+
+impl<D: FlashWrite> FlashWrite for RateLimited<'_, D> {
+    fn lazy_erase(&self) -> bool {
+        self.flash.lazy_erase()
+    }
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.delay();
+        self.flash.erase_block(location)
+    }
+    fn coarse_erase_sizes(&self) -> &[usize] {
+        self.flash.coarse_erase_sizes()
+    }
+    fn erase_coarse(
+        &self,
+        location: ErasableLocation,
+        size: usize,
+    ) -> Result<()> {
+        self.delay();
+        self.flash.erase_coarse(location, size)
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.delay();
+        self.flash.erase_and_write_block(location, buffer)
+    }
+    fn write_erased_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.flash.write_erased_block(location, buffer)
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use super::*;
+    use crate::mem::MemFlash;
+    use crate::ErasableRange;
+    const KIB: usize = 1024; // B
+
+    #[test]
+    fn rate_limited_delays() -> Result<()> {
+        let mut storage = [0u8; 32 * KIB];
+        let mut delays = 0;
+        let mut delay = || delays += 1;
+        {
+            let mem = MemFlash::new(&mut storage[..], 4 * KIB)?;
+            let flash = RateLimited::new(mem, &mut delay);
+            let beginning = flash.erasable_location(0).unwrap();
+            flash.erase_range(&ErasableRange::new(
+                beginning,
+                beginning.advance(12 * KIB)?,
+            ))?;
+            flash.erase_and_write_blocks(
+                beginning.advance(16 * KIB)?,
+                &[0x42; 6 * KIB],
+            )?;
+            let mut buf = [0u8; 4];
+            flash.read_exact(20 * KIB as Location, &mut buf)?;
+            assert_eq!(buf, [0x42; 4]);
+            flash.into_inner();
+        }
+        assert_eq!(delays, 5);
+        assert!(storage[..12 * KIB].iter().all(|&x| x == 0xff));
+        Ok(())
+    }
+}
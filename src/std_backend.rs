@@ -0,0 +1,281 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableLocation, FlashAlign, FlashRead, FlashWrite, Location};
+use crate::{Error, Result};
+use core::convert::TryFrom;
+use core::mem::MaybeUninit;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+/// Flash chip backed by a file (for example a flash image for host
+/// tools), which keeps its size: accesses past its end return Error::Io.
+/// The file is locked across each seek and transfer, so a FileFlash can be
+/// shared between threads.
+pub struct FileFlash {
+    file: Mutex<File>,
+    capacity: usize,
+    erasable_block_size: usize,
+}
+
+impl FileFlash {
+    /// Opens the existing file at PATH for reading and writing.
+    /// Note: ERASABLE_BLOCK_SIZE has to be a power of two, and the size of
+    /// the file has to be a multiple of it.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        erasable_block_size: usize,
+    ) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let capacity = file.metadata()?.len();
+        Self::new(file, usize::try_from(capacity)?, erasable_block_size)
+    }
+    /// Creates a file of CAPACITY Byte at PATH (replacing any existing
+    /// one), with all its blocks erased.
+    /// Note: ERASABLE_BLOCK_SIZE has to be a power of two, and CAPACITY has
+    /// to be a multiple of it.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        capacity: usize,
+        erasable_block_size: usize,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let result = Self::new(file, capacity, erasable_block_size)?;
+        let erased = vec![0xffu8; erasable_block_size];
+        let mut file = result.locked(0, capacity)?;
+        for _ in 0..capacity / erasable_block_size {
+            file.write_all(&erased)?;
+        }
+        drop(file);
+        Ok(result)
+    }
+    fn new(
+        file: File,
+        capacity: usize,
+        erasable_block_size: usize,
+    ) -> Result<Self> {
+        if !erasable_block_size.is_power_of_two()
+            || capacity % erasable_block_size != 0
+        {
+            return Err(Error::Alignment);
+        }
+        Location::try_from(capacity)?;
+        Ok(Self { file: Mutex::new(file), capacity, erasable_block_size })
+    }
+    pub fn into_inner(self) -> File {
+        // Every access seeks first, so a panic in another holder of the
+        // lock does not leave anything behind that matters.
+        self.file.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+    /// Locks the file and seeks to BEGINNING, if [BEGINNING, BEGINNING +
+    /// SIZE) is within the file (otherwise returns Error::Io).
+    fn locked(
+        &self,
+        beginning: Location,
+        size: usize,
+    ) -> Result<MutexGuard<'_, File>> {
+        (beginning as usize)
+            .checked_add(size)
+            .filter(|&end| end <= self.capacity)
+            .ok_or_else(|| {
+                Error::io_caused_by(Error::OutOfBounds { location: beginning })
+            })?;
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.seek(SeekFrom::Start(beginning.into()))?;
+        Ok(file)
+    }
+}
+
+impl FlashRead for FileFlash {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        self.locked(beginning, buffer.len())?.read_exact(buffer)?;
+        Ok(())
+    }
+    fn capacity(&self) -> Option<usize> {
+        Some(self.capacity)
+    }
+    /// Reads through a small initialized buffer, since std::io::Read can
+    /// only fill initialized memory; this saves zeroing all of BUFFER.
+    fn read_exact_uninit<'a>(
+        &self,
+        beginning: Location,
+        buffer: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8]> {
+        let mut file = self.locked(beginning, buffer.len())?;
+        let mut chunk = [0u8; 512];
+        for part in buffer.chunks_mut(chunk.len()) {
+            let chunk = &mut chunk[..part.len()];
+            file.read_exact(chunk)?;
+            for (x, &y) in part.iter_mut().zip(chunk.iter()) {
+                x.write(y);
+            }
+        }
+        // SAFETY: All of BUFFER was just initialized, chunk by chunk.
+        Ok(unsafe { crate::assume_init(buffer) })
+    }
+}
+
+impl FlashAlign for FileFlash {
+    fn erasable_block_size(&self) -> usize {
+        self.erasable_block_size
+    }
+}
+
+impl FlashWrite for FileFlash {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.erase_and_write_block(location, &[])
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        let mut block = vec![0xffu8; self.erasable_block_size];
+        block[..buffer.len()].copy_from_slice(buffer);
+        self.locked(self.location(location)?, self.erasable_block_size)?
+            .write_all(&block)?;
+        Ok(())
+    }
+    /// Writes just BUFFER, since the remainder is already erased.
+    fn write_erased_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Programmer);
+        }
+        self.locked(self.location(location)?, buffer.len())?
+            .write_all(buffer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod std_backend_tests {
+    use super::*;
+    use crate::ErasableRange;
+    use std::path::PathBuf;
+    const KIB: usize = 1024; // B
+
+    /// Path of a temporary file that is removed again on drop.
+    struct TempPath(PathBuf);
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let name = format!("amd-flash-{}-{}", std::process::id(), name);
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn file_flash_reopen() -> Result<()> {
+        let path = TempPath::new("reopen");
+        let flash = FileFlash::create(&path.0, 16 * KIB, 4 * KIB)?;
+        let beginning = flash.erasable_location(4 * KIB as Location).unwrap();
+        let mut data = [0u8; 6 * KIB];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        flash.erase_and_write_blocks(beginning, &data)?;
+        drop(flash);
+
+        let flash = FileFlash::open(&path.0, 4 * KIB)?;
+        assert_eq!(flash.capacity(), Some(16 * KIB));
+        let mut buf = [0u8; 10 * KIB];
+        flash.read_exact(2 * KIB as Location, &mut buf)?;
+        assert!(buf[..2 * KIB].iter().all(|&x| x == 0xff));
+        assert_eq!(buf[2 * KIB..8 * KIB], data);
+        assert!(buf[8 * KIB..].iter().all(|&x| x == 0xff));
+        let mut uninit = [MaybeUninit::uninit(); 6 * KIB];
+        assert_eq!(
+            flash.read_exact_uninit(4 * KIB as Location, &mut uninit)?,
+            data
+        );
+
+        flash.erase_range(&ErasableRange::new(
+            beginning,
+            beginning.advance(4 * KIB)?,
+        ))?;
+        flash.read_exact(4 * KIB as Location, &mut buf[..8 * KIB])?;
+        assert!(buf[..4 * KIB].iter().all(|&x| x == 0xff));
+        assert_eq!(buf[4 * KIB..6 * KIB], data[4 * KIB..]);
+        Ok(())
+    }
+
+    #[test]
+    fn file_flash_write_erased_block() -> Result<()> {
+        let path = TempPath::new("write-erased-block");
+        let flash = FileFlash::create(path.0.as_path(), 8 * KIB, 4 * KIB)?;
+        let location = flash.erasable_location(4 * KIB as Location).unwrap();
+        flash.erase_and_write_block(location, &[1; 4])?;
+        // Only writes BUFFER, and leaves the remainder alone.
+        flash.write_erased_block(location, &[2, 3])?;
+        let mut buf = [0u8; 6];
+        flash.read_exact(4 * KIB as Location, &mut buf)?;
+        assert_eq!(buf, [2, 3, 1, 1, 0xff, 0xff]);
+        Ok(())
+    }
+
+    #[test]
+    fn file_flash_threads() -> Result<()> {
+        let path = TempPath::new("threads");
+        let flash = FileFlash::create(path.0.as_path(), 16 * KIB, 4 * KIB)?;
+        std::thread::scope(|scope| {
+            for i in 0..4u8 {
+                let flash = &flash;
+                scope.spawn(move || {
+                    let beginning = i as Location * 4 * KIB as Location;
+                    let location = flash.erasable_location(beginning).unwrap();
+                    let data = [i; 4 * KIB];
+                    let mut buf = [0u8; 4 * KIB];
+                    for _ in 0..50 {
+                        flash.erase_and_write_block(location, &data).unwrap();
+                        flash.read_exact(beginning, &mut buf).unwrap();
+                        assert_eq!(buf, data);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn file_flash_out_of_bounds() -> Result<()> {
+        let path = TempPath::new("out-of-bounds");
+        let flash = FileFlash::create(&path.0, 8 * KIB, 4 * KIB)?;
+        let mut buf = [0u8; 2];
+        assert!(matches!(
+            flash.read_exact(8 * KIB as Location - 1, &mut buf),
+            Err(Error::Io { .. })
+        ));
+        let location = flash.erasable_location(8 * KIB as Location).unwrap();
+        assert!(matches!(
+            flash.erase_and_write_block(location, &[0u8; 4]),
+            Err(Error::Io { .. })
+        ));
+        assert!(matches!(flash.erase_block(location), Err(Error::Io { .. })));
+        let file = flash.into_inner();
+        assert_eq!(file.metadata().unwrap().len(), 8 * KIB as u64);
+        assert!(matches!(
+            FileFlash::open(&path.0, 16 * KIB),
+            Err(Error::Alignment)
+        ));
+        Ok(())
+    }
+}
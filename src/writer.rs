@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{ErasableLocation, FlashWrite};
+use crate::{Error, Result};
+
+/// Writes a stream of data of arbitrary chunk sizes to consecutive
+/// erasable blocks, staging it in a caller-provided buffer until a block
+/// (or a batch of blocks) is full.
+pub struct BlockWriter<'a, F: FlashWrite> {
+    flash: &'a F,
+    location: ErasableLocation,
+    buffer: &'a mut [u8],
+    batch_size: usize,
+    staged: usize,
+}
+
+impl<'a, F: FlashWrite> BlockWriter<'a, F> {
+    /// Starts writing at LOCATION.
+    /// BUFFER is used for staging and has to be at least
+    /// erasable_block_size() long.
+    pub fn new(
+        flash: &'a F,
+        location: ErasableLocation,
+        buffer: &'a mut [u8],
+    ) -> Result<Self> {
+        flash.location(location)?;
+        if buffer.len() < flash.erasable_block_size() {
+            return Err(Error::Size);
+        }
+        Ok(Self { flash, location, buffer, batch_size: 1, staged: 0 })
+    }
+    /// Stage up to BATCH_SIZE blocks before writing them all with one
+    /// erase_and_write_blocks call.
+    /// The staging buffer has to be large enough for BATCH_SIZE blocks.
+    pub fn with_batch_size(self, batch_size: usize) -> Result<Self> {
+        let size = batch_size
+            .checked_mul(self.flash.erasable_block_size())
+            .ok_or(Error::Overflow)?;
+        if batch_size == 0 || size > self.buffer.len() {
+            return Err(Error::Size);
+        }
+        Ok(Self { batch_size, ..self })
+    }
+    fn batch_capacity(&self) -> usize {
+        self.batch_size * self.flash.erasable_block_size()
+    }
+    fn flush(&mut self) -> Result<()> {
+        if self.staged > 0 {
+            self.flash.erase_and_write_blocks(
+                self.location,
+                &self.buffer[..self.staged],
+            )?;
+            self.location = self.location.advance_at_least(self.staged)?;
+            self.staged = 0;
+        }
+        Ok(())
+    }
+    /// Appends DATA, writing out every batch that became full.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let mut data = data;
+        while !data.is_empty() {
+            let capacity = self.batch_capacity();
+            let len = data.len().min(capacity - self.staged);
+            self.buffer[self.staged..self.staged + len]
+                .copy_from_slice(&data[..len]);
+            self.staged += len;
+            data = &data[len..];
+            if self.staged == capacity {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+    /// Writes out what is still staged (erasing the remainder of the last
+    /// block) and returns the location after the last block written.
+    pub fn finish(mut self) -> Result<ErasableLocation> {
+        self.flush()?;
+        Ok(self.location)
+    }
+}
+
+/// Where BlockPacker::add placed a blob.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    /// The erasable block that contains the blob
+    pub location: ErasableLocation,
+    /// Offset of the blob in that block, in Byte
+    pub offset: usize,
+}
+
+/// Packs blobs that are smaller than an erasable block into as few
+/// consecutive erasable blocks as possible, keeping them in order.
+/// A blob never straddles two blocks: if it doesn't fit into the rest of
+/// the current block, it goes to the beginning of the next one.
+pub struct BlockPacker {
+    location: ErasableLocation,
+    /// Number of Byte used in the block at LOCATION
+    used: usize,
+    blocks: usize,
+}
+
+impl BlockPacker {
+    /// Starts packing into the block at BEGINNING.
+    pub fn new(beginning: ErasableLocation) -> Self {
+        Self { location: beginning, used: 0, blocks: 0 }
+    }
+    /// Places BLOB after the previous one.
+    /// BLOB must not be larger than an erasable block (otherwise returns
+    /// Error::Size).
+    pub fn add(&mut self, blob: &[u8]) -> Result<Placement> {
+        let erasable_block_size = self.location.erasable_block_size();
+        if blob.len() > erasable_block_size {
+            return Err(Error::Size);
+        }
+        if self.blocks == 0 {
+            self.blocks = 1;
+        } else if blob.len() > erasable_block_size - self.used {
+            self.location = self.location.next_block()?;
+            self.used = 0;
+            self.blocks += 1;
+        }
+        let placement =
+            Placement { location: self.location, offset: self.used };
+        self.used += blob.len();
+        Ok(placement)
+    }
+    /// Number of erasable blocks that contain blobs.
+    pub fn blocks_used(&self) -> usize {
+        self.blocks
+    }
+}
+
+#[cfg(test)]
+mod writer_tests {
+    use super::*;
+    use crate::tests::FlashImage;
+    use crate::{FlashAlign, FlashRead, Location};
+    use core::cell::Cell;
+    const KIB: usize = 1024; // B
+
+    /// Counts the erase_and_write_blocks calls.
+    struct Counting<'a> {
+        flash: FlashImage<'a>,
+        writes: Cell<usize>,
+    }
+    impl FlashRead for Counting<'_> {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.flash.read_exact(location, buffer)
+        }
+    }
+    impl FlashAlign for Counting<'_> {
+        fn erasable_block_size(&self) -> usize {
+            self.flash.erasable_block_size()
+        }
+    }
+    impl FlashWrite for Counting<'_> {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.flash.erase_block(location)
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.flash.erase_and_write_block(location, buffer)
+        }
+        fn erase_and_write_blocks(
+            &self,
+            location: ErasableLocation,
+            buf: &[u8],
+        ) -> Result<()> {
+            self.writes.set(self.writes.get() + 1);
+            self.flash.erase_and_write_blocks(location, buf)
+        }
+    }
+
+    fn write_pattern(batch_size: usize) -> Result<usize> {
+        let mut storage = [0u8; 64 * KIB];
+        let flash = Counting {
+            flash: FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]),
+            writes: Cell::new(0),
+        };
+        let mut data = [0u8; 16 * KIB + 100];
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = (i % 251) as u8;
+        }
+        let mut buffer = [0u8; 16 * KIB];
+        let beginning = flash.erasable_location(4 * KIB as u32).unwrap();
+        let mut writer = BlockWriter::new(&flash, beginning, &mut buffer)?
+            .with_batch_size(batch_size)?;
+        for chunk in data.chunks(1000) {
+            writer.write(chunk)?;
+        }
+        let end = writer.finish()?;
+        assert_eq!(Location::from(end), 24 * KIB as u32);
+        let mut buf = [0u8; 20 * KIB];
+        flash.read_exact(4 * KIB as u32, &mut buf)?;
+        assert_eq!(buf[..data.len()], data);
+        assert!(buf[data.len()..].iter().all(|&x| x == 0xff));
+        Ok(flash.writes.get())
+    }
+
+    #[test]
+    fn block_writer_unbatched() -> Result<()> {
+        assert_eq!(write_pattern(1)?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn block_writer_batched() -> Result<()> {
+        assert_eq!(write_pattern(4)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn block_packer() -> Result<()> {
+        let mut storage = [0u8; 0];
+        let flash = FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let beginning = flash.erasable_location(8 * KIB as u32).unwrap();
+        let mut packer = BlockPacker::new(beginning);
+        assert_eq!(packer.blocks_used(), 0);
+        let blob = [0u8; KIB];
+        let placements =
+            [packer.add(&blob)?, packer.add(&blob)?, packer.add(&blob)?];
+        for (i, placement) in placements.iter().enumerate() {
+            assert_eq!(Location::from(placement.location), 8 * KIB as u32);
+            assert_eq!(placement.offset, i * KIB);
+        }
+        assert_eq!(packer.blocks_used(), 1);
+
+        // Doesn't fit into the remaining 1 KiB
+        let placement = packer.add(&[0u8; 2 * KIB])?;
+        assert_eq!(Location::from(placement.location), 12 * KIB as u32);
+        assert_eq!(placement.offset, 0);
+        let placement = packer.add(&[0u8; 2 * KIB])?;
+        assert_eq!(Location::from(placement.location), 12 * KIB as u32);
+        assert_eq!(placement.offset, 2 * KIB);
+        assert_eq!(packer.blocks_used(), 2);
+        assert!(matches!(packer.add(&[0u8; 4 * KIB + 1]), Err(Error::Size)));
+        let placement = packer.add(&[0u8; 4 * KIB])?;
+        assert_eq!(Location::from(placement.location), 16 * KIB as u32);
+        assert_eq!(packer.blocks_used(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn block_writer_batch_too_big() {
+        let mut storage = [0u8; 16 * KIB];
+        let flash = FlashImage::with_geometry(&mut storage[..], 4 * KIB, &[]);
+        let mut buffer = [0u8; 8 * KIB];
+        let beginning = flash.erasable_location(0).unwrap();
+        let writer = BlockWriter::new(&flash, beginning, &mut buffer).unwrap();
+        assert!(matches!(writer.with_batch_size(3), Err(Error::Size)));
+    }
+}